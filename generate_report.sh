@@ -3,13 +3,15 @@
 # Build tool
 cargo build --release
 
-# make project
-rm -rf output
-mkdir output
+# make project, keeping the feature cache
+mkdir -p output
+find output -mindepth 1 -maxdepth 1 ! -name cache -exec rm -rf {} +
 mkdir output/img
 mkdir output/docs
 mkdir output/audio
 mkdir output/encoder
+mkdir output/annotations
+mkdir -p output/cache
 
 # Run
 ./target/release/super_fast_spectrogram $1 > output/log.txt
@@ -1,6 +1,6 @@
 use crate::discovery::Discovery;
 use crate::numerics::*;
-use crate::spectrogram::NDSequence;
+use crate::spectrogram::{ClusterInput, NDSequence};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -11,18 +11,22 @@ use std::thread;
 pub struct AlignmentWorkers {
     pub data: Arc<Vec<NDSequence>>,
     pub result: Arc<Mutex<Vec<f32>>>,
+    /// best frequency shift in Hz of each alignment
+    pub offsets: Arc<Mutex<Vec<f32>>>,
 }
 
 impl AlignmentWorkers {
     pub fn new(data: Vec<NDSequence>) -> AlignmentWorkers {
         let n = data.len();
         let data = Arc::from(data);
-        let mut alignments = vec![];
-        for _ in 0..n * n {
-            alignments.push(0.0);
-        }
+        let alignments = vec![0.0; n * n];
         let result = Arc::from(Mutex::from(alignments));
-        AlignmentWorkers { data, result }
+        let offsets = Arc::from(Mutex::from(vec![0.0; n * n]));
+        AlignmentWorkers {
+            data,
+            result,
+            offsets,
+        }
     }
 
     /**
@@ -37,7 +41,9 @@ impl AlignmentWorkers {
             let stop = usize::min((batch + 1) * batch_size, n);
             let data = self.data.clone();
             let result = self.result.clone();
+            let offsets = self.offsets.clone();
             let params = params.clone();
+            let shift = params.shift_params();
             let th = thread::spawn(move || {
                 for i in start..stop {
                     println!(
@@ -51,10 +57,13 @@ impl AlignmentWorkers {
                         if i != j {
                             let len = usize::max(data[i].len(), data[j].len());
                             let params = params.alignment_params(len);
-                            let mut alignment = Alignment::new();
-                            alignment.construct_alignment(&data[i], &data[j], &params);
+                            let candidates = shift.offsets(&data[i], &data[j]);
+                            let (alignment, offset) =
+                                Alignment::best_offset(&data[i], &data[j], &params, &candidates);
                             let mut result = result.lock().unwrap();
                             result[i * n + j] = alignment.score();
+                            let mut offsets = offsets.lock().unwrap();
+                            offsets[i * n + j] = offset.hz(shift.bin_hz);
                         }
                     }
                 }
@@ -67,12 +76,146 @@ impl AlignmentWorkers {
     }
 }
 
+/**
+ * How alignments account for calls transposed in frequency
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FrequencyShift {
+    /// align the sequences as they are
+    None,
+    /// align after moving the mean frequency of one sequence onto the other
+    Mean,
+    /// best alignment of all shifts up to the given Hz in both directions
+    Search(f32),
+}
+
+/**
+ * Frequency shift of the second sequence of an alignment
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum Offset {
+    /// frames are compared as they are
+    None,
+    /// Hz added to every frame of a contour
+    Hz(f32),
+    /// bins every spectrogram frame is moved up
+    Bins(isize),
+}
+
+impl Offset {
+    /**
+     * Euclidean distance of two frames after shifting the second.
+     * Shifted spectrogram frames are compared where they overlap,
+     * scaled to the length of the frames.
+     */
+    pub fn distance(&self, x: &[f32], y: &[f32]) -> f32 {
+        match *self {
+            Offset::None => euclidean(x, y),
+            Offset::Hz(hz) => f32::sqrt(
+                x.iter()
+                    .zip(y)
+                    .map(|(x, y)| f32::powf(x - y - hz, 2.0))
+                    .sum(),
+            ),
+            Offset::Bins(bins) => {
+                let shift = bins.unsigned_abs();
+                if shift >= x.len() {
+                    return f32::INFINITY;
+                }
+                let (x, y) = if bins >= 0 {
+                    (&x[shift..], &y[..y.len() - shift])
+                } else {
+                    (&x[..x.len() - shift], &y[shift..])
+                };
+                euclidean(x, y) * f32::sqrt((x.len() + shift) as f32 / x.len() as f32)
+            }
+        }
+    }
+
+    /**
+     * The shift in Hz given the width of a spectrogram bin
+     */
+    pub fn hz(&self, bin_hz: f32) -> f32 {
+        match *self {
+            Offset::None => 0.0,
+            Offset::Hz(hz) => hz,
+            Offset::Bins(bins) => bins as f32 * bin_hz,
+        }
+    }
+}
+
+/**
+ * Parameters of frequency shift invariant alignment
+ */
+#[derive(Clone, Debug)]
+pub struct ShiftParams {
+    pub input: ClusterInput,
+    pub shift: FrequencyShift,
+    /// Hz between two searched shifts
+    pub step: f32,
+    /// width of a spectrogram bin in Hz
+    pub bin_hz: f32,
+}
+
+impl ShiftParams {
+    /**
+     * Candidate shifts of the second sequence. Contours are shifted in Hz,
+     * spectrograms by whole bins, other features are never shifted.
+     */
+    pub fn offsets(&self, x: &NDSequence, y: &NDSequence) -> Vec<Offset> {
+        match (self.input, self.shift) {
+            (ClusterInput::Features, _) | (_, FrequencyShift::None) => vec![Offset::None],
+            (ClusterInput::Contour, FrequencyShift::Mean) => {
+                vec![Offset::Hz(mean(&x.frames) - mean(&y.frames))]
+            }
+            (ClusterInput::Contour, FrequencyShift::Search(max_shift)) => {
+                let steps = (max_shift / self.step.max(1e-6)) as isize;
+                (-steps..=steps)
+                    .map(|i| Offset::Hz(i as f32 * self.step))
+                    .collect()
+            }
+            (ClusterInput::Spectrogram, FrequencyShift::Mean) => {
+                vec![Offset::Bins(peak_bin(x) as isize - peak_bin(y) as isize)]
+            }
+            (ClusterInput::Spectrogram, FrequencyShift::Search(max_shift)) => {
+                let step = usize::max((self.step / self.bin_hz).round() as usize, 1);
+                let max_bins = (max_shift / self.bin_hz) as isize;
+                (-max_bins..=max_bins)
+                    .step_by(step)
+                    .map(Offset::Bins)
+                    .collect()
+            }
+        }
+    }
+}
+
+/**
+ * Bin of the highest value of the mean frame
+ */
+fn peak_bin(x: &NDSequence) -> usize {
+    let mut mean_frame = vec![0.0; x.n_bins];
+    for t in 0..x.len() {
+        for (m, v) in mean_frame.iter_mut().zip(x.vec(t)) {
+            *m += v;
+        }
+    }
+    let mut peak = 0;
+    for (bin, m) in mean_frame.iter().enumerate() {
+        if *m > mean_frame[peak] {
+            peak = bin;
+        }
+    }
+    peak
+}
+
 /**
  * Parameters of alignment
  *
  * The warping band is described by sakoe and chiba.
  * The restart threshold allows for local alignments.
  * The insertion, deletion and match penalty allow to weigh errors differently.
+ * The offset shifts the frames of the second sequence in frequency.
  */
 #[derive(Clone, Debug)]
 pub struct AlignmentParams {
@@ -80,6 +223,7 @@ pub struct AlignmentParams {
     pub insertion_penalty: f32,
     pub deletion_penalty: f32,
     pub match_penalty: f32,
+    pub offset: Offset,
 }
 
 impl AlignmentParams {
@@ -89,6 +233,7 @@ impl AlignmentParams {
             insertion_penalty: 1.0,
             deletion_penalty: 1.0,
             match_penalty: 1.0,
+            offset: Offset::None,
         }
     }
 }
@@ -103,6 +248,12 @@ pub struct Alignment {
     pub sparse: HashMap<(usize, usize), f32>,
 }
 
+impl Default for Alignment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Alignment {
     pub fn new() -> Alignment {
         let mut sparse = HashMap::new();
@@ -115,11 +266,11 @@ impl Alignment {
      */
     pub fn score(&self) -> f32 {
         if self.m == 0 && self.n == 0 {
-            std::f32::INFINITY
+            f32::INFINITY
         } else {
             match self.sparse.get(&(self.n - 1, self.m - 1)) {
                 Some(score) => score / (self.n + self.m) as f32,
-                None => std::f32::INFINITY,
+                None => f32::INFINITY,
             }
         }
     }
@@ -134,21 +285,21 @@ impl Alignment {
         y: &NDSequence,
         params: &AlignmentParams,
     ) -> f32 {
-        let distance = euclidean(x.vec(i - 1), y.vec(j - 1));
+        let distance = params.offset.distance(x.vec(i - 1), y.vec(j - 1));
         // Check for a match on the diagonal
         let match_score = match self.sparse.get(&(i - 1, j - 1)) {
             Some(score) => *score,
-            None => std::f32::INFINITY,
+            None => f32::INFINITY,
         };
         // Check for an insertion error
         let insert_score = match self.sparse.get(&(i - 1, j)) {
             Some(score) => *score,
-            None => std::f32::INFINITY,
+            None => f32::INFINITY,
         };
         // Check for a deletion error
         let delete_score = match self.sparse.get(&(i, j - 1)) {
             Some(score) => *score,
-            None => std::f32::INFINITY,
+            None => f32::INFINITY,
         };
         if delete_score < match_score && delete_score < insert_score {
             delete_score + params.deletion_penalty * distance
@@ -173,9 +324,37 @@ impl Alignment {
         let w = usize::max(params.warping_band, abs(self.n, self.m)) + 2;
         for i in 1..=self.n {
             for j in usize::max(diff(i, w), 1)..usize::min(i + w, self.m + 1) {
-                let node = self.alignment_score(i, j, &x, &y, params);
+                let node = self.alignment_score(i, j, x, y, params);
                 self.sparse.insert((i, j), node);
             }
         }
     }
+
+    /**
+     * Align under each of the offsets of the second sequence.
+     * Returns the alignment with the best score and its offset.
+     */
+    pub fn best_offset(
+        x: &NDSequence,
+        y: &NDSequence,
+        params: &AlignmentParams,
+        offsets: &[Offset],
+    ) -> (Alignment, Offset) {
+        let mut best: Option<(Alignment, Offset)> = None;
+        for offset in offsets {
+            let params = AlignmentParams {
+                offset: *offset,
+                ..params.clone()
+            };
+            let mut alignment = Alignment::new();
+            alignment.construct_alignment(x, y, &params);
+            if best
+                .as_ref()
+                .is_none_or(|(best, _)| alignment.score() < best.score())
+            {
+                best = Some((alignment, *offset));
+            }
+        }
+        best.unwrap_or_else(|| (Alignment::new(), Offset::None))
+    }
 }
@@ -1,40 +1,821 @@
-use hound::*;
+use crate::decoding::*;
+use crate::error::*;
+use crate::numerics::*;
+use hound::{SampleFormat, WavSpec};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
 use std::iter::FromIterator;
 
 /**
- * Simply holds audio data from hound
+ * How multi channel recordings are turned into sequences
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Channels {
+    /// Always use the channel with this index
+    Fixed(usize),
+    /// Average all channels into one
+    Mixdown,
+    /// Use the channel with the highest energy in each file
+    Loudest,
+    /// Every channel is its own sequence
+    Separate,
+}
+
+/**
+ * Simply holds audio data from hound.
+ * Samples are kept as floats in [-1, 1] regardless of the
+ * source format, the spec remembers the original bit depth.
  */
 #[derive(Clone)]
 pub struct AudioData {
     pub id: usize,
     pub spec: WavSpec,
-    pub data: Vec<i16>,
+    /// channel in the source file, none for a mixdown
+    pub channel: Option<usize>,
+    pub data: Vec<f32>,
 }
 
-impl AudioData {
+/**
+ * Where a slice of audio was cut from
+ */
+#[derive(Clone, Debug)]
+pub struct Provenance {
+    pub source: String,
+    pub channel: Option<usize>,
+    pub start: usize,
+    pub stop: usize,
+    pub sample_rate: u32,
+}
+
+impl Provenance {
     /**
-     * Read audio data. For multiple channels, we only take the first.
+     * Channel as used in file names and reports
      */
-    pub fn from_file(file: &str, id: usize) -> AudioData {
-        let mut reader = WavReader::open(file).unwrap();
-        let n_channels = reader.spec().channels as usize;
-        let samples = reader
-            .samples::<i16>()
-            .enumerate()
-            .filter_map(|(i, x)| {
-                if i % n_channels == 0 {
-                    Some(x.unwrap())
-                } else {
-                    None
-                }
+    pub fn channel_name(&self) -> String {
+        channel_name(self.channel)
+    }
+
+    /**
+     * Start in seconds
+     */
+    pub fn start_seconds(&self) -> f32 {
+        self.start as f32 / self.sample_rate as f32
+    }
+
+    /**
+     * Stop in seconds
+     */
+    pub fn stop_seconds(&self) -> f32 {
+        self.stop as f32 / self.sample_rate as f32
+    }
+
+    /**
+     * File name, channel and time range as shown in audio editors
+     */
+    pub fn label(&self) -> String {
+        let file = std::path::Path::new(&self.source)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.source.clone());
+        format!(
+            "{} channel {} [{:.3}s - {:.3}s]",
+            file,
+            self.channel_name(),
+            self.start_seconds(),
+            self.stop_seconds()
+        )
+    }
+}
+
+/**
+ * A labeled region of a wav file, in samples
+ */
+#[derive(Clone, Debug)]
+pub struct CueMarker {
+    pub start: usize,
+    pub len: usize,
+    pub label: String,
+}
+
+/**
+ * Append a `cue ` chunk and a `LIST/adtl` chunk with a label and
+ * a region length for each marker to a finished wav file
+ */
+fn write_cue_markers(file: &str, markers: &[CueMarker]) -> Result<()> {
+    let mut cue = vec![];
+    cue.extend(&(markers.len() as u32).to_le_bytes());
+    for (id, marker) in markers.iter().enumerate() {
+        cue.extend(&(id as u32 + 1).to_le_bytes());
+        cue.extend(&(marker.start as u32).to_le_bytes());
+        cue.extend(b"data");
+        cue.extend(&0u32.to_le_bytes());
+        cue.extend(&0u32.to_le_bytes());
+        cue.extend(&(marker.start as u32).to_le_bytes());
+    }
+    let mut adtl = b"adtl".to_vec();
+    for (id, marker) in markers.iter().enumerate() {
+        let mut labl = (id as u32 + 1).to_le_bytes().to_vec();
+        labl.extend(marker.label.as_bytes());
+        labl.push(0);
+        push_chunk(&mut adtl, b"labl", &labl);
+        let mut ltxt = (id as u32 + 1).to_le_bytes().to_vec();
+        ltxt.extend(&(marker.len as u32).to_le_bytes());
+        ltxt.extend(b"rgn ");
+        ltxt.extend(&[0u8; 8]);
+        push_chunk(&mut adtl, b"ltxt", &ltxt);
+    }
+    let mut chunks = vec![];
+    push_chunk(&mut chunks, b"cue ", &cue);
+    push_chunk(&mut chunks, b"LIST", &adtl);
+
+    let mut fp = OpenOptions::new().read(true).write(true).open(file)?;
+    let mut len = fp.seek(SeekFrom::End(0))?;
+    if len % 2 == 1 {
+        fp.write_all(&[0])?;
+        len += 1;
+    }
+    fp.write_all(&chunks)?;
+    fp.seek(SeekFrom::Start(4))?;
+    fp.write_all(&((len + chunks.len() as u64 - 8) as u32).to_le_bytes())?;
+    Ok(())
+}
+
+/**
+ * Append a riff chunk, padded to an even size
+ */
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend(id);
+    out.extend(&(data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Zero crossings of the interpolation kernel on each side
+const RESAMPLE_ZEROS: usize = 16;
+/// Kernel values stored between two input samples
+const RESAMPLE_OVERSAMPLING: usize = 512;
+/// Cutoff relative to the lower nyquist frequency
+const RESAMPLE_ROLLOFF: f32 = 0.94;
+/// Shape of the kaiser window of the kernel
+const RESAMPLE_BETA: f32 = 8.0;
+
+/**
+ * Band limited resampling by kaiser windowed sinc interpolation.
+ * The input is consumed block by block, the resampler keeps the
+ * samples it still needs from the previous block.
+ */
+pub struct Resampler {
+    from: u64,
+    to: u64,
+    /// kernel radius in input samples
+    radius: usize,
+    /// kernel sampled at `RESAMPLE_OVERSAMPLING` points per input sample
+    kernel: Vec<f32>,
+    /// input samples starting at input position `start`
+    history: Vec<f32>,
+    start: i64,
+    /// number of input samples seen so far
+    n_input: u64,
+    /// next output sample
+    next: u64,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Resampler {
+        let cutoff = f32::min(1.0, to as f32 / from as f32) * RESAMPLE_ROLLOFF;
+        let radius = f32::ceil(RESAMPLE_ZEROS as f32 / cutoff) as usize;
+        let kernel = (0..=(radius + 1) * RESAMPLE_OVERSAMPLING)
+            .map(|i| {
+                let x = i as f32 / RESAMPLE_OVERSAMPLING as f32;
+                cutoff * sinc(cutoff * x) * kaiser_at(x / radius as f32, RESAMPLE_BETA)
             })
             .collect();
-        let mut spec = reader.spec();
+        Resampler {
+            from: u64::from(from),
+            to: u64::from(to),
+            radius,
+            kernel,
+            history: vec![0.0; radius],
+            start: -(radius as i64),
+            n_input: 0,
+            next: 0,
+        }
+    }
+
+    /**
+     * Interpolated kernel at distance x from its center
+     */
+    fn kernel_at(&self, x: f32) -> f32 {
+        let pos = x.abs() * RESAMPLE_OVERSAMPLING as f32;
+        let i = pos as usize;
+        if i + 1 >= self.kernel.len() {
+            0.0
+        } else {
+            let frac = pos - i as f32;
+            self.kernel[i] * (1.0 - frac) + self.kernel[i + 1] * frac
+        }
+    }
+
+    /**
+     * Compute all output samples the history allows up to the output sample `limit`
+     */
+    fn produce(&mut self, limit: u64) -> Vec<f32> {
+        let mut output = vec![];
+        let end = self.start + self.history.len() as i64;
+        while self.next < limit {
+            let pos = self.next * self.from;
+            let center = (pos / self.to) as i64;
+            let frac = (pos % self.to) as f32 / self.to as f32;
+            if center + self.radius as i64 >= end {
+                break;
+            }
+            let mut sample = 0.0;
+            for k in (center - self.radius as i64 + 1)..=(center + self.radius as i64) {
+                let x = self.history[(k - self.start) as usize];
+                sample += x * self.kernel_at((center - k) as f32 + frac);
+            }
+            output.push(sample);
+            self.next += 1;
+        }
+        let center = (self.next * self.from / self.to) as i64;
+        let keep_from = i64::min(center - self.radius as i64 + 1, end);
+        if keep_from > self.start {
+            self.history.drain(0..(keep_from - self.start) as usize);
+            self.start = keep_from;
+        }
+        output
+    }
+
+    /**
+     * Resample the next block of input
+     */
+    pub fn process(&mut self, block: &[f32]) -> Vec<f32> {
+        self.n_input += block.len() as u64;
+        self.history.extend_from_slice(block);
+        self.produce(u64::MAX)
+    }
+
+    /**
+     * Samples still pending at the end of the input
+     */
+    pub fn flush(&mut self) -> Vec<f32> {
+        let n_output = (self.n_input * self.to).div_ceil(self.from);
+        self.history.extend(vec![0.0; 2 * self.radius + 1]);
+        self.produce(n_output)
+    }
+}
+
+/**
+ * Loudness normalization with target levels in dBFS
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalization {
+    None,
+    Peak(f32),
+    Rms(f32),
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
+
+/**
+ * Butterworth filter of the pre processing chain with cutoffs in Hz
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    None,
+    Highpass(f32),
+    Lowpass(f32),
+    Bandpass(f32, f32),
+}
+
+impl Filter {
+    /**
+     * Cutoffs have to lie strictly between zero and the nyquist frequency,
+     * the low cutoff of a band pass below the high one
+     */
+    pub fn validate(&self, sample_rate: u32) -> Result<()> {
+        let nyquist = sample_rate as f32 / 2.0;
+        let valid = |cutoff: f32| cutoff > 0.0 && cutoff < nyquist;
+        let ok = match *self {
+            Filter::None => true,
+            Filter::Highpass(cutoff) | Filter::Lowpass(cutoff) => valid(cutoff),
+            Filter::Bandpass(low, high) => valid(low) && valid(high) && low < high,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(DiscoveryError::InvalidConfig(format!(
+                "filter {:?} needs ascending cutoffs between 0 and {} Hz",
+                self, nyquist
+            )))
+        }
+    }
+}
+
+/**
+ * Parameters of the pre processing chain
+ *
+ * The dc removal is a one pole high pass,
+ * a pre emphasis coefficient of zero disables pre emphasis.
+ */
+#[derive(Clone, Debug)]
+pub struct PreprocessingParams {
+    pub dc_removal: bool,
+    pub pre_emphasis: f32,
+    pub filter: Filter,
+    pub filter_order: usize,
+}
+
+/// Pole of the dc removal filter
+const DC_POLE: f32 = 0.995;
+
+/**
+ * Second order section in direct form one.
+ * First order sections have zero second coefficients.
+ */
+#[derive(Clone, Debug)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn new(b: [f32; 3], a: [f32; 3]) -> Biquad {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    /**
+     * Butterworth sections of the given order, as cookbook biquads with
+     * the butterworth pole quality factors and one first order section
+     * for odd orders. The poles lie at angles (2k + 1)π / 2N from the
+     * real axis for even orders and at kπ / N next to the real pole
+     * for odd orders.
+     */
+    fn butterworth(order: usize, cutoff: f32, sample_rate: u32, highpass: bool) -> Vec<Biquad> {
+        let nyquist = sample_rate as f32 / 2.0;
+        let cutoff = f32::min(cutoff, 0.99 * nyquist);
+        let w0 = std::f32::consts::PI * cutoff / nyquist;
+        let (sin, cos) = (f32::sin(w0), f32::cos(w0));
+        let mut sections = vec![];
+        for k in 0..order / 2 {
+            let angle = if order.is_multiple_of(2) {
+                std::f32::consts::PI * (2 * k + 1) as f32 / (2 * order) as f32
+            } else {
+                std::f32::consts::PI * (k + 1) as f32 / order as f32
+            };
+            let q = 1.0 / (2.0 * f32::cos(angle));
+            let alpha = sin / (2.0 * q);
+            let a = [1.0 + alpha, -2.0 * cos, 1.0 - alpha];
+            let b = if highpass {
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0]
+            } else {
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0]
+            };
+            sections.push(Biquad::new(b, a));
+        }
+        if order % 2 == 1 {
+            let k = f32::tan(w0 / 2.0);
+            let a = [k + 1.0, k - 1.0, 0.0];
+            let b = if highpass {
+                [1.0, -1.0, 0.0]
+            } else {
+                [k, k, 0.0]
+            };
+            sections.push(Biquad::new(b, a));
+        }
+        sections
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/**
+ * Pre processing chain: dc removal, pre emphasis and a butterworth filter.
+ * The filter state is kept between blocks, so streams can be processed
+ * block by block.
+ */
+pub struct Preprocessor {
+    params: PreprocessingParams,
+    sections: Vec<Biquad>,
+    last_x: f32,
+    last_y: f32,
+    last_emphasis: f32,
+}
+
+impl Preprocessor {
+    pub fn new(params: &PreprocessingParams, sample_rate: u32) -> Preprocessor {
+        let order = params.filter_order;
+        let sections = match params.filter {
+            Filter::None => vec![],
+            Filter::Highpass(cutoff) => Biquad::butterworth(order, cutoff, sample_rate, true),
+            Filter::Lowpass(cutoff) => Biquad::butterworth(order, cutoff, sample_rate, false),
+            Filter::Bandpass(low, high) => {
+                let mut sections = Biquad::butterworth(order, low, sample_rate, true);
+                sections.extend(Biquad::butterworth(order, high, sample_rate, false));
+                sections
+            }
+        };
+        Preprocessor {
+            params: params.clone(),
+            sections,
+            last_x: 0.0,
+            last_y: 0.0,
+            last_emphasis: 0.0,
+        }
+    }
+
+    /**
+     * Process the next block of samples in place
+     */
+    pub fn process(&mut self, block: &mut [f32]) {
+        for sample in block.iter_mut() {
+            let mut x = *sample;
+            if self.params.dc_removal {
+                let y = x - self.last_x + DC_POLE * self.last_y;
+                self.last_x = x;
+                self.last_y = y;
+                x = y;
+            }
+            if self.params.pre_emphasis > 0.0 {
+                let y = x - self.params.pre_emphasis * self.last_emphasis;
+                self.last_emphasis = x;
+                x = y;
+            }
+            for section in self.sections.iter_mut() {
+                x = section.process(x);
+            }
+            *sample = x;
+        }
+    }
+}
+
+/**
+ * Reads one selected channel of a recording block by block, resampled
+ * to a target rate. Long recordings never have to be in memory at once.
+ */
+pub struct AudioStream {
+    pub id: usize,
+    pub file: String,
+    /// mono spec at the output sample rate
+    pub spec: WavSpec,
+    /// channel in the source file, none for a mixdown
+    pub channel: Option<usize>,
+    n_channels: usize,
+    reader: AudioReader,
+    resampler: Option<Resampler>,
+    done: bool,
+}
+
+impl AudioStream {
+    /**
+     * Open a stream for each selected channel.
+     * Without a sample rate the source rate is kept.
+     */
+    pub fn open(
+        file: &str,
+        id: usize,
+        channels: Channels,
+        sample_rate: Option<u32>,
+    ) -> Result<Vec<AudioStream>> {
+        let n_channels = AudioReader::open(file)?.spec.channels as usize;
+        let selected: Vec<Option<usize>> = match channels {
+            Channels::Fixed(c) if c >= n_channels => {
+                return Err(DiscoveryError::Audio(format!(
+                    "channel {} requested but {} has {} channels",
+                    c, file, n_channels
+                )))
+            }
+            Channels::Fixed(c) => vec![Some(c)],
+            Channels::Mixdown => vec![None],
+            Channels::Loudest => vec![Some(AudioStream::loudest(file)?)],
+            Channels::Separate => (0..n_channels).map(Some).collect(),
+        };
+        selected
+            .into_iter()
+            .map(|channel| AudioStream::new(file, id, channel, sample_rate))
+            .collect()
+    }
+
+    fn new(
+        file: &str,
+        id: usize,
+        channel: Option<usize>,
+        sample_rate: Option<u32>,
+    ) -> Result<AudioStream> {
+        let reader = AudioReader::open(file)?;
+        let mut spec = reader.spec;
+        let n_channels = spec.channels as usize;
+        let source_rate = spec.sample_rate;
         spec.channels = 1;
-        AudioData {
+        spec.sample_rate = sample_rate.unwrap_or(source_rate);
+        let resampler = if spec.sample_rate != source_rate {
+            Some(Resampler::new(source_rate, spec.sample_rate))
+        } else {
+            None
+        };
+        Ok(AudioStream {
             id,
+            file: file.to_string(),
+            spec,
+            channel,
+            n_channels,
+            reader,
+            resampler,
+            done: false,
+        })
+    }
+
+    /**
+     * Channel with the highest energy in a file
+     */
+    fn loudest(file: &str) -> Result<usize> {
+        let mut reader = AudioReader::open(file)?;
+        let n_channels = reader.spec.channels as usize;
+        let mut energy = vec![0.0; n_channels];
+        while let Some(block) = reader.next_block()? {
+            for frame in block.chunks(n_channels) {
+                for (c, x) in frame.iter().enumerate() {
+                    energy[c] += x * x;
+                }
+            }
+        }
+        let mut loudest = 0;
+        for (c, e) in energy.iter().enumerate() {
+            if *e > energy[loudest] {
+                loudest = c;
+            }
+        }
+        Ok(loudest)
+    }
+
+    /**
+     * Start reading from the beginning again
+     */
+    pub fn rewind(&mut self) -> Result<()> {
+        *self = AudioStream::new(
+            &self.file,
+            self.id,
+            self.channel,
+            Some(self.spec.sample_rate),
+        )?;
+        Ok(())
+    }
+
+    /**
+     * Next block of samples, none at the end of the recording
+     */
+    pub fn next_block(&mut self) -> Result<Option<Vec<f32>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let block = match self.reader.next_block()? {
+            Some(block) => {
+                let mono: Vec<f32> = match self.channel {
+                    Some(c) => block
+                        .iter()
+                        .skip(c)
+                        .step_by(self.n_channels)
+                        .cloned()
+                        .collect(),
+                    None => block
+                        .chunks(self.n_channels)
+                        .map(|frame| frame.iter().sum::<f32>() / self.n_channels as f32)
+                        .collect(),
+                };
+                match &mut self.resampler {
+                    Some(resampler) => Some(resampler.process(&mono)),
+                    None => Some(mono),
+                }
+            }
+            None => {
+                self.done = true;
+                self.resampler.as_mut().map(|resampler| resampler.flush())
+            }
+        };
+        Ok(block)
+    }
+
+    /**
+     * Read the rest of the recording into memory
+     */
+    pub fn read_all(mut self) -> Result<AudioData> {
+        let mut data = vec![];
+        while let Some(block) = self.next_block()? {
+            data.extend(block);
+        }
+        Ok(self.slice_data(data))
+    }
+
+    /**
+     * Cut the sample ranges `[start, stop)` from the rest of the recording.
+     * The ranges have to be sorted by their start. Each slice is handed
+     * to the callback as soon as it is complete, so only the slices
+     * that are currently cut are held in memory.
+     */
+    pub fn slices<F>(&mut self, ranges: &[(usize, usize)], mut f: F) -> Result<()>
+    where
+        F: FnMut(usize, usize, AudioData) -> Result<()>,
+    {
+        let mut cutter = SliceCutter::new(ranges);
+        while let Some(block) = self.next_block()? {
+            for (start, stop, data) in cutter.push(&block) {
+                f(start, stop, self.slice_data(data))?;
+            }
+        }
+        for (start, stop, data) in cutter.finish() {
+            f(start, stop, self.slice_data(data))?;
+        }
+        Ok(())
+    }
+
+    fn slice_data(&self, data: Vec<f32>) -> AudioData {
+        AudioData {
+            id: self.id,
+            spec: self.spec,
+            channel: self.channel,
+            data,
+        }
+    }
+}
+
+/**
+ * Cuts sorted sample ranges `[start, stop)` from a stream of blocks.
+ * Only the ranges overlapping the samples seen so far are held.
+ */
+struct SliceCutter<'a> {
+    pending: std::iter::Peekable<std::slice::Iter<'a, (usize, usize)>>,
+    active: Vec<(usize, usize, Vec<f32>)>,
+    pos: usize,
+}
+
+impl<'a> SliceCutter<'a> {
+    fn new(ranges: &'a [(usize, usize)]) -> SliceCutter<'a> {
+        SliceCutter {
+            pending: ranges.iter().peekable(),
+            active: vec![],
+            pos: 0,
+        }
+    }
+
+    /**
+     * Add the next block of samples, returns the ranges completed by it
+     */
+    fn push(&mut self, block: &[f32]) -> Vec<(usize, usize, Vec<f32>)> {
+        let (pos, end) = (self.pos, self.pos + block.len());
+        while let Some((start, stop)) = self.pending.peek() {
+            if *start >= end {
+                break;
+            }
+            self.active
+                .push((*start, *stop, Vec::with_capacity(stop - start)));
+            self.pending.next();
+        }
+        for (start, stop, data) in self.active.iter_mut() {
+            let from = usize::max(*start, pos);
+            let to = usize::min(*stop, end);
+            if from < to {
+                data.extend_from_slice(&block[from - pos..to - pos]);
+            }
+        }
+        let active = std::mem::take(&mut self.active);
+        let (complete, open): (Vec<_>, Vec<_>) =
+            active.into_iter().partition(|(_, stop, _)| *stop <= end);
+        self.active = open;
+        self.pos = end;
+        complete
+    }
+
+    /**
+     * The ranges still open at the end of the recording, cut at its end
+     */
+    fn finish(self) -> Vec<(usize, usize, Vec<f32>)> {
+        let pos = self.pos;
+        self.active
+            .into_iter()
+            .map(|(start, _, data)| (start, pos, data))
+            .collect()
+    }
+}
+
+/**
+ * A channel index or `mix` for a mixdown
+ */
+pub fn channel_name(channel: Option<usize>) -> String {
+    match channel {
+        Some(c) => format!("{}", c),
+        None => "mix".to_string(),
+    }
+}
+
+impl AudioData {
+    /**
+     * Read audio data. For multiple channels, we only take the first.
+     */
+    pub fn from_file(file: &str, id: usize) -> Result<AudioData> {
+        Ok(AudioData::channels_from_file(file, id, Channels::Fixed(0))?.remove(0))
+    }
+
+    /**
+     * Read audio data and select channels. Depending on the selection
+     * we get one sequence or one sequence for each channel.
+     * Wav files with integer samples of any bit depth and float samples,
+     * flac, ogg vorbis and mp3 files are supported.
+     */
+    pub fn channels_from_file(file: &str, id: usize, channels: Channels) -> Result<Vec<AudioData>> {
+        AudioStream::open(file, id, channels, None)?
+            .into_iter()
+            .map(|stream| stream.read_all())
+            .collect()
+    }
+
+    /**
+     * This audio after the pre processing chain
+     */
+    pub fn preprocess(&self, params: &PreprocessingParams) -> AudioData {
+        let mut preprocessed = self.clone();
+        Preprocessor::new(params, self.spec.sample_rate).process(&mut preprocessed.data);
+        preprocessed
+    }
+
+    /**
+     * This audio at a different sample rate
+     */
+    pub fn resample(&self, sample_rate: u32) -> AudioData {
+        if sample_rate == self.spec.sample_rate {
+            return self.clone();
+        }
+        let mut resampler = Resampler::new(self.spec.sample_rate, sample_rate);
+        let mut data = resampler.process(&self.data);
+        data.extend(resampler.flush());
+        let mut spec = self.spec;
+        spec.sample_rate = sample_rate;
+        AudioData {
+            id: self.id,
             spec,
-            data: samples,
+            channel: self.channel,
+            data,
+        }
+    }
+
+    /**
+     * Raised cosine fade in and fade out over the given number of samples,
+     * at most half of the audio each
+     */
+    pub fn fade(&mut self, samples: usize) {
+        let n = self.data.len();
+        let samples = usize::min(samples, n / 2);
+        for i in 0..samples {
+            let gain = 0.5 - 0.5 * f32::cos(std::f32::consts::PI * i as f32 / samples as f32);
+            self.data[i] *= gain;
+            self.data[n - 1 - i] *= gain;
+        }
+    }
+
+    /**
+     * Scale the audio to the target level,
+     * never beyond full scale
+     */
+    pub fn normalize(&mut self, normalization: Normalization) {
+        let peak = self
+            .data
+            .iter()
+            .fold(0.0, |peak: f32, x| f32::max(peak, x.abs()));
+        if peak <= 0.0 {
+            return;
+        }
+        let gain = match normalization {
+            Normalization::None => return,
+            Normalization::Peak(db) => db_to_gain(db) / peak,
+            Normalization::Rms(db) => {
+                let rms = f32::sqrt(
+                    self.data.iter().map(|x| x * x).sum::<f32>() / self.data.len() as f32,
+                );
+                f32::min(db_to_gain(db) / rms, 1.0 / peak)
+            }
+        };
+        for sample in self.data.iter_mut() {
+            *sample *= gain;
         }
     }
 
@@ -43,7 +824,7 @@ impl AudioData {
      */
     pub fn append(&mut self, insert_zeros: usize, audio: &mut AudioData) {
         for _i in 0..insert_zeros {
-            self.data.push(0);
+            self.data.push(0.0);
         }
         self.data.append(&mut audio.data);
     }
@@ -55,17 +836,248 @@ impl AudioData {
         AudioData {
             id: self.id,
             spec: self.spec,
+            channel: self.channel,
             data: Vec::from_iter(self.data[t_start..t_stop].iter().cloned()),
         }
     }
 
     /**
-     * Write this audio file
+     * Write this audio file in the sample format and bit depth of its spec
      */
-    pub fn write(&self, file: String) {
-        let mut writer = hound::WavWriter::create(file, self.spec).unwrap();
-        for sample in self.data.iter() {
-            writer.write_sample(*sample).unwrap();
+    pub fn write(&self, file: String) -> Result<()> {
+        let mut writer = hound::WavWriter::create(file, self.spec)?;
+        match self.spec.sample_format {
+            SampleFormat::Float => {
+                for sample in self.data.iter() {
+                    writer.write_sample(*sample)?;
+                }
+            }
+            SampleFormat::Int => {
+                let scale = full_scale(self.spec.bits_per_sample);
+                for sample in self.data.iter() {
+                    let quantized = f32::round(sample * scale).clamp(-scale, scale - 1.0);
+                    writer.write_sample(quantized as i32)?;
+                }
+            }
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+
+    /**
+     * Write to a wav file with a labeled cue marker for each region
+     */
+    pub fn write_with_markers(&self, file: String, markers: &[CueMarker]) -> Result<()> {
+        self.write(file.clone())?;
+        write_cue_markers(&file, markers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                0.5 * f32::sin(
+                    2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32,
+                )
+            })
+            .collect()
+    }
+
+    fn resample(data: &[f32], from: u32, to: u32, block: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(from, to);
+        let mut output = vec![];
+        for chunk in data.chunks(block) {
+            output.extend(resampler.process(chunk));
         }
+        output.extend(resampler.flush());
+        output
+    }
+
+    #[test]
+    fn resampled_length_follows_the_ratio() {
+        let data = vec![0.0; 44100];
+        assert_eq!(resample(&data, 44100, 48000, 4096).len(), 48000);
+        assert_eq!(resample(&data, 44100, 16000, 4096).len(), 16000);
+        assert_eq!(resample(&data[..1001], 48000, 44100, 100).len(), 920);
+    }
+
+    #[test]
+    fn resampled_sine_keeps_frequency_and_amplitude() {
+        for &(from, to) in &[(44100, 48000), (48000, 16000), (22050, 96000)] {
+            let output = resample(&sine(1000.0, from, from as usize), from, to, 1024);
+            let expected = sine(1000.0, to, to as usize);
+            // away from the zero padded edges
+            let middle = to as usize / 10..to as usize * 9 / 10;
+            for i in middle {
+                assert!((output[i] - expected[i]).abs() < 1e-2, "{} -> {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn blockwise_resampling_matches_one_block() {
+        let data = sine(3000.0, 44100, 10000);
+        let whole = resample(&data, 44100, 48000, data.len());
+        for &block in &[1, 7, 333, 4096] {
+            assert_eq!(resample(&data, 44100, 48000, block), whole);
+        }
+    }
+
+    fn cut(data: &[f32], ranges: &[(usize, usize)], block: usize) -> Vec<(usize, usize, Vec<f32>)> {
+        let mut cutter = SliceCutter::new(ranges);
+        let mut slices = vec![];
+        for chunk in data.chunks(block) {
+            slices.extend(cutter.push(chunk));
+        }
+        slices.extend(cutter.finish());
+        slices.sort_by_key(|(start, _, _)| *start);
+        slices
+    }
+
+    #[test]
+    fn streamed_slices_match_the_whole_recording() {
+        let data: Vec<f32> = (0..5000).map(|i| i as f32).collect();
+        // overlapping, across block boundaries and open at the end
+        let ranges = [
+            (0, 10),
+            (95, 1300),
+            (1000, 1001),
+            (2400, 2999),
+            (4000, 6000),
+        ];
+        for &block in &[1, 7, 97, 512, 1000, 4999, 5000, 8192] {
+            let slices = cut(&data, &ranges, block);
+            assert_eq!(slices.len(), ranges.len(), "block {}", block);
+            for (&(start, stop, ref slice), &(from, to)) in slices.iter().zip(ranges.iter()) {
+                let to = usize::min(to, data.len());
+                assert_eq!((start, stop), (from, to), "block {}", block);
+                assert_eq!(&slice[..], &data[from..to], "block {}", block);
+            }
+        }
+    }
+
+    fn gain_db(sections: &[Biquad], frequency: f32, sample_rate: u32) -> f32 {
+        use rustfft::num_complex::Complex;
+        let w = 2.0 * std::f32::consts::PI * frequency / sample_rate as f32;
+        let z1 = Complex::from_polar(&1.0, &-w);
+        let z2 = z1 * z1;
+        let mut gain = 1.0;
+        for section in sections {
+            let b = section.b[0] + z1 * section.b[1] + z2 * section.b[2];
+            let a = Complex::new(1.0, 0.0) + z1 * section.a[0] + z2 * section.a[1];
+            gain *= (b / a).norm();
+        }
+        20.0 * f32::log10(gain)
+    }
+
+    #[test]
+    fn butterworth_is_3db_down_at_the_cutoff() {
+        for order in 1..=5 {
+            for &highpass in &[true, false] {
+                let sections = Biquad::butterworth(order, 1000.0, 16000, highpass);
+                let gain = gain_db(&sections, 1000.0, 16000);
+                assert!(
+                    (gain + 3.0103).abs() < 0.01,
+                    "order {} highpass {}: {} dB",
+                    order,
+                    highpass,
+                    gain
+                );
+            }
+        }
+    }
+
+    fn riff_chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = vec![];
+        let mut pos = 0;
+        while pos + 8 <= bytes.len() {
+            let len = u32::from_le_bytes([
+                bytes[pos + 4],
+                bytes[pos + 5],
+                bytes[pos + 6],
+                bytes[pos + 7],
+            ]) as usize;
+            chunks.push((&bytes[pos..pos + 4], &bytes[pos + 8..pos + 8 + len]));
+            pos += 8 + len + len % 2;
+        }
+        chunks
+    }
+
+    fn u32_at(bytes: &[u8], pos: usize) -> usize {
+        u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize
+    }
+
+    #[test]
+    fn cue_markers_can_be_read_back() {
+        let file = std::env::temp_dir()
+            .join(format!("cue_markers_{}.wav", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let audio = AudioData {
+            id: 0,
+            spec: WavSpec {
+                channels: 1,
+                sample_rate: 8000,
+                bits_per_sample: 8,
+                sample_format: SampleFormat::Int,
+            },
+            channel: None,
+            data: vec![0.0; 1001],
+        };
+        let markers = vec![
+            CueMarker {
+                start: 10,
+                len: 100,
+                label: "odd".to_string(),
+            },
+            CueMarker {
+                start: 500,
+                len: 401,
+                label: "even".to_string(),
+            },
+        ];
+        audio.write_with_markers(file.clone(), &markers).unwrap();
+        let bytes = std::fs::read(&file).unwrap();
+
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let chunks = riff_chunks(&bytes[12..]);
+        let ids: Vec<&[u8]> = chunks.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![&b"fmt "[..], b"data", b"cue ", b"LIST"]);
+
+        let cue = chunks[2].1;
+        assert_eq!(u32_at(cue, 0), markers.len());
+        for (i, marker) in markers.iter().enumerate() {
+            let point = &cue[4 + 24 * i..4 + 24 * (i + 1)];
+            assert_eq!(u32_at(point, 0), i + 1);
+            assert_eq!(u32_at(point, 4), marker.start);
+            assert_eq!(&point[8..12], b"data");
+            assert_eq!(u32_at(point, 20), marker.start);
+        }
+
+        let list = chunks[3].1;
+        assert_eq!(&list[0..4], b"adtl");
+        let adtl = riff_chunks(&list[4..]);
+        assert_eq!(adtl.len(), 2 * markers.len());
+        for (i, marker) in markers.iter().enumerate() {
+            let (id, labl) = adtl[2 * i];
+            assert_eq!(id, b"labl");
+            assert_eq!(u32_at(labl, 0), i + 1);
+            assert_eq!(&labl[4..], format!("{}\0", marker.label).as_bytes());
+            let (id, ltxt) = adtl[2 * i + 1];
+            assert_eq!(id, b"ltxt");
+            assert_eq!(u32_at(ltxt, 0), i + 1);
+            assert_eq!(u32_at(ltxt, 4), marker.len);
+            assert_eq!(&ltxt[8..12], b"rgn ");
+        }
+
+        let reader = hound::WavReader::open(&file).unwrap();
+        assert_eq!(reader.len(), 1001);
+        std::fs::remove_file(&file).unwrap();
     }
 }
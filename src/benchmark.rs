@@ -0,0 +1,174 @@
+use rand::{thread_rng, Rng};
+use rustfft::num_complex::Complex;
+use rustfft::num_traits::Zero;
+use rustfft::FFTplanner;
+use std::time::Instant;
+
+use crate::audio::*;
+use crate::discovery::*;
+use crate::spectrogram::*;
+
+/**
+ * Magnitude spectra by a complex fft over the real input
+ * with new buffers for every frame, as computed before the real fft
+ */
+fn reference_magnitudes(
+    data: &[f32],
+    fft_size: usize,
+    fft_step: usize,
+    window: Window,
+) -> Vec<Vec<f32>> {
+    let fft = FFTplanner::new(false).plan_fft(fft_size);
+    let coefficients = window.coefficients(fft_size);
+    (fft_size..data.len())
+        .step_by(fft_step)
+        .map(|i| {
+            let mut output: Vec<Complex<f32>> = vec![Complex::zero(); fft_size];
+            let mut input: Vec<Complex<f32>> = data[i - fft_size..i]
+                .iter()
+                .enumerate()
+                .map(|(i, x)| Complex::new(x * coefficients[i], 0.0))
+                .collect();
+            fft.process(&mut input[..], &mut output);
+            output
+                .iter()
+                .map(|complex| f32::sqrt(complex.norm_sqr()))
+                .take(fft_size / 2)
+                .collect()
+        })
+        .collect()
+}
+
+/// Runs of each measurement, the fastest is reported
+const RUNS: usize = 5;
+
+/**
+ * Result and fastest time in seconds of repeated runs
+ */
+fn fastest<T, F: FnMut() -> T>(mut f: F) -> (T, f32) {
+    let mut best = f32::INFINITY;
+    let mut result = None;
+    for _ in 0..RUNS {
+        let now = Instant::now();
+        result = Some(f());
+        best = f32::min(best, now.elapsed().as_secs_f32());
+    }
+    (result.unwrap(), best)
+}
+
+fn max_difference(x: &[Vec<f32>], y: &[Vec<f32>]) -> f32 {
+    x.iter()
+        .zip(y)
+        .flat_map(|(x, y)| x.iter().zip(y).map(|(x, y)| (x - y).abs()))
+        .fold(0.0, f32::max)
+}
+
+/**
+ * Noise with a whistle sweeping up from 5 kHz to 15 kHz every two seconds
+ */
+fn synthetic(seconds: usize, sample_rate: u32) -> AudioData {
+    let mut rng = thread_rng();
+    let rate = sample_rate as f32;
+    let data = (0..seconds * sample_rate as usize)
+        .map(|i| {
+            let t = (i as f32 / rate) % 2.0;
+            let whistle = if t < 0.5 {
+                let phase = 2.0 * std::f32::consts::PI * (5000.0 * t + 10000.0 * t * t);
+                0.5 * f32::sin(phase)
+            } else {
+                0.0
+            };
+            whistle + rng.gen_range(-0.1, 0.1)
+        })
+        .collect();
+    AudioData {
+        id: 0,
+        spec: hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        },
+        channel: Some(0),
+        data,
+    }
+}
+
+/**
+ * Time the spectrogram of a synthetic signal of the given length
+ * with the reference complex fft and the real fft
+ */
+pub fn spectrogram(discover: &Discovery, seconds: usize) {
+    let params = discover.features();
+    let audio = synthetic(seconds, discover.sample_rate);
+    println!(
+        "==== Benchmark: {}s at {} Hz, dft {} step {} ====",
+        seconds, discover.sample_rate, params.fft_size, params.fft_step
+    );
+
+    let (reference, t_reference) = fastest(|| {
+        reference_magnitudes(&audio.data, params.fft_size, params.fft_step, params.window)
+    });
+    println!("complex fft, buffer per frame:  {:.3}s", t_reference);
+
+    let spectrum = Spectrum::new(params.fft_size, params.window);
+    let (sequential, t_sequential) = fastest(|| {
+        let mut scratch = spectrum.scratch();
+        (params.fft_size..audio.data.len())
+            .step_by(params.fft_step)
+            .map(|i| spectrum.magnitude(&audio.data[i - params.fft_size..i], &mut scratch))
+            .collect::<Vec<_>>()
+    });
+    println!(
+        "real fft, reused buffers:       {:.3}s ({:.1}x)",
+        t_sequential,
+        t_reference / t_sequential
+    );
+
+    let (parallel, t_parallel) =
+        fastest(|| spectrum.audio_magnitudes(&audio.data, params.fft_step));
+    println!(
+        "real fft, parallel frames:      {:.3}s ({:.1}x, {} threads)",
+        t_parallel,
+        t_reference / t_parallel,
+        rayon::current_num_threads()
+    );
+
+    let error = f32::max(
+        max_difference(&reference, &sequential),
+        max_difference(&reference, &parallel),
+    );
+    println!("max magnitude difference:       {:e}", error);
+
+    let (sequence, t_sequence) = fastest(|| NDSequence::new(&params, &audio, None));
+    println!(
+        "features of {} frames:       {:.3}s",
+        sequence.len(),
+        t_sequence
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn real_fft_matches_complex_fft() {
+        let audio = synthetic(1, 8000);
+        for &(fft_size, window) in &[(256, Window::Hamming), (512, Window::Kaiser(8.0))] {
+            let step = fft_size / 4;
+            let reference = reference_magnitudes(&audio.data, fft_size, step, window);
+            let spectrum = Spectrum::new(fft_size, window);
+            let mut scratch = spectrum.scratch();
+            let real: Vec<Vec<f32>> = (fft_size..audio.data.len())
+                .step_by(step)
+                .map(|i| spectrum.magnitude(&audio.data[i - fft_size..i], &mut scratch))
+                .collect();
+            assert_eq!(reference.len(), real.len());
+            assert!(max_difference(&reference, &real) < 1e-3);
+            assert!(
+                max_difference(&reference, &spectrum.audio_magnitudes(&audio.data, step)) < 1e-3
+            );
+        }
+    }
+}
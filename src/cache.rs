@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use crate::error::*;
+use crate::spectrogram::NDSequence;
+
+/**
+ * 64 bit FNV-1a hash, stable across runs and builds
+ */
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Version of the cached features, bump it whenever a change
+/// of the code changes the features computed from the same parameters
+const FEATURE_VERSION: u32 = 1;
+
+/**
+ * On disk cache of the features of audio files.
+ * Features are stored by the path and decoded samples of the audio file
+ * and the parameters they were computed with.
+ */
+pub struct FeatureCache {
+    folder: String,
+}
+
+impl FeatureCache {
+    pub fn new(folder: &str) -> Result<FeatureCache> {
+        std::fs::create_dir_all(folder)?;
+        Ok(FeatureCache {
+            folder: folder.to_string(),
+        })
+    }
+
+    /**
+     * Cache key of the samples of an audio file and a description of the parameters,
+     * together with the feature version
+     */
+    pub fn key(&self, file: &str, samples: &[f32], params: &str) -> u64 {
+        let hash = fnv1a(FNV_OFFSET, file.as_bytes());
+        let hash = samples
+            .iter()
+            .fold(hash, |hash, sample| fnv1a(hash, &sample.to_le_bytes()));
+        let params = format!("v{} {}", FEATURE_VERSION, params);
+        fnv1a(hash, params.as_bytes())
+    }
+
+    fn path(&self, key: u64) -> String {
+        format!("{}/{:016x}.bin", self.folder, key)
+    }
+
+    /**
+     * Cached features, none if they were not computed yet or can not be read
+     */
+    pub fn load(&self, key: u64) -> Option<NDSequence> {
+        let path = self.path(key);
+        if Path::new(&path).exists() {
+            NDSequence::from_file(&path).ok()
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Write the features to a temporary file first and move it in place,
+     * so a crashed or concurrent run never leaves a truncated entry
+     */
+    pub fn store(&self, key: u64, sequence: &NDSequence) -> Result<()> {
+        let path = self.path(key);
+        let tmp = format!("{}.{}.tmp", path, std::process::id());
+        sequence.save_file(&tmp)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
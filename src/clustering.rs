@@ -1,3 +1,4 @@
+use crate::error::*;
 use crate::numerics::*;
 use std::collections::{HashMap, HashSet};
 
@@ -37,6 +38,19 @@ pub struct AgglomerativeClustering {
 }
 
 impl AgglomerativeClustering {
+    /**
+     * Member of a cluster with the smallest sum of distances to the other members
+     */
+    pub fn medoid(cluster: &[usize], distances: &[f32], n_instances: usize) -> Option<usize> {
+        cluster.iter().cloned().min_by(|i, j| {
+            let sum_i: f32 = cluster.iter().map(|k| distances[i * n_instances + k]).sum();
+            let sum_j: f32 = cluster.iter().map(|k| distances[j * n_instances + k]).sum();
+            sum_i
+                .partial_cmp(&sum_j)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+    }
+
     pub fn cluster_sets(
         operations: &[ClusteringOperation],
         cluster_ids: &HashSet<usize>,
@@ -82,7 +96,7 @@ impl AgglomerativeClustering {
         distances: Vec<f32>,
         n_instances: usize,
         perc: f32,
-    ) -> (Vec<ClusteringOperation>, HashSet<usize>) {
+    ) -> Result<(Vec<ClusteringOperation>, HashSet<usize>)> {
         let n_clusters = n_instances;
         println!("\tset parents to self");
         let mut parents = vec![];
@@ -98,7 +112,7 @@ impl AgglomerativeClustering {
         };
         println!("\testimate threshold");
         let mut cluster_result = vec![];
-        let threshold = percentile(&mut distances.clone(), perc);
+        let threshold = percentile(&mut distances.clone(), perc)?;
         println!("Clustering with {}", threshold);
         let mut distance = 0.0;
         while dendrogram.n_clusters > 1 && distance < threshold {
@@ -106,7 +120,7 @@ impl AgglomerativeClustering {
             distance = operation.distance;
             cluster_result.push(operation);
         }
-        (cluster_result, dendrogram.clusters())
+        Ok((cluster_result, dendrogram.clusters()))
     }
 
     /**
@@ -157,8 +171,8 @@ impl AgglomerativeClustering {
         for x in 0..assignment.len() {
             if assignment[x] == i {
                 size_y = 0.0;
-                for y in 0..assignment.len() {
-                    if assignment[y] == j {
+                for (y, cluster) in assignment.iter().enumerate() {
+                    if *cluster == j {
                         distance += self.distances[x * self.n_instances + y];
                         size_y += 1.0;
                     }
@@ -175,7 +189,7 @@ impl AgglomerativeClustering {
     pub fn merge(&mut self) -> ClusteringOperation {
         let assignment = self.assignment();
         let clusters = &self.clusters();
-        let mut min_linkage = std::f32::INFINITY;
+        let mut min_linkage = f32::INFINITY;
         let mut min_merge: (usize, usize) = (0, 0);
         for target_i in clusters {
             for target_j in clusters {
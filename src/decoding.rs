@@ -0,0 +1,177 @@
+use crate::error::*;
+use claxon::FlacReader;
+use hound::{SampleFormat, WavReader, WavSpec};
+use lewton::inside_ogg::OggStreamReader;
+use minimp3::Decoder;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Samples per channel read at once from formats without frames
+const BLOCK_SIZE: usize = 65536;
+
+/**
+ * Largest magnitude of a signed integer sample with the given bit depth
+ */
+pub fn full_scale(bits_per_sample: u16) -> f32 {
+    (1i64 << (bits_per_sample - 1)) as f32
+}
+
+/**
+ * The decoders for all supported formats
+ */
+enum Source {
+    Wav(WavReader<BufReader<File>>),
+    Flac(FlacReader<File>),
+    Ogg(Box<OggStreamReader<File>>),
+    Mp3(Decoder<File>),
+}
+
+/**
+ * Reads interleaved samples as floats in [-1, 1] from
+ * wav, flac, ogg vorbis or mp3 files. The format is chosen by extension.
+ * The spec describes the source: compressed formats without
+ * a bit depth are described as 16 bit.
+ */
+pub struct AudioReader {
+    pub spec: WavSpec,
+    source: Source,
+    pending: Vec<f32>,
+}
+
+impl AudioReader {
+    pub fn open(file: &str) -> Result<AudioReader> {
+        let extension = Path::new(file)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let reader = match extension.as_str() {
+            "wav" | "wave" => {
+                let reader = WavReader::open(file)?;
+                AudioReader {
+                    spec: reader.spec(),
+                    source: Source::Wav(reader),
+                    pending: vec![],
+                }
+            }
+            "flac" => {
+                let reader = FlacReader::open(file)?;
+                let info = reader.streaminfo();
+                AudioReader {
+                    spec: WavSpec {
+                        channels: info.channels as u16,
+                        sample_rate: info.sample_rate,
+                        bits_per_sample: info.bits_per_sample as u16,
+                        sample_format: SampleFormat::Int,
+                    },
+                    source: Source::Flac(reader),
+                    pending: vec![],
+                }
+            }
+            "ogg" | "oga" => {
+                let reader = OggStreamReader::new(File::open(file)?)?;
+                AudioReader {
+                    spec: WavSpec {
+                        channels: u16::from(reader.ident_hdr.audio_channels),
+                        sample_rate: reader.ident_hdr.audio_sample_rate,
+                        bits_per_sample: 16,
+                        sample_format: SampleFormat::Int,
+                    },
+                    source: Source::Ogg(Box::new(reader)),
+                    pending: vec![],
+                }
+            }
+            "mp3" => {
+                let mut decoder = Decoder::new(File::open(file)?);
+                let frame = decoder.next_frame()?;
+                AudioReader {
+                    spec: WavSpec {
+                        channels: frame.channels as u16,
+                        sample_rate: frame.sample_rate as u32,
+                        bits_per_sample: 16,
+                        sample_format: SampleFormat::Int,
+                    },
+                    source: Source::Mp3(decoder),
+                    pending: from_i16(&frame.data),
+                }
+            }
+            _ => {
+                return Err(DiscoveryError::Audio(format!(
+                    "unsupported format: {}",
+                    file
+                )))
+            }
+        };
+        Ok(reader)
+    }
+
+    /**
+     * Next block of interleaved samples, none at the end of the file.
+     * Blocks always hold complete frames of all channels.
+     */
+    pub fn next_block(&mut self) -> Result<Option<Vec<f32>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.pending)));
+        }
+        let block_size = BLOCK_SIZE * self.spec.channels as usize;
+        let block: Vec<f32> = match &mut self.source {
+            Source::Wav(reader) => match self.spec.sample_format {
+                SampleFormat::Float => reader
+                    .samples::<f32>()
+                    .take(block_size)
+                    .collect::<std::result::Result<_, _>>()?,
+                SampleFormat::Int => {
+                    let scale = full_scale(self.spec.bits_per_sample);
+                    reader
+                        .samples::<i32>()
+                        .take(block_size)
+                        .map(|x| x.map(|x| x as f32 / scale))
+                        .collect::<std::result::Result<_, _>>()?
+                }
+            },
+            Source::Flac(reader) => {
+                let scale = full_scale(self.spec.bits_per_sample);
+                reader
+                    .samples()
+                    .take(block_size)
+                    .map(|x| x.map(|x| x as f32 / scale))
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            Source::Ogg(reader) => loop {
+                match reader.read_dec_packet_itl()? {
+                    Some(packet) if packet.is_empty() => continue,
+                    Some(packet) => break from_i16(&packet),
+                    None => break vec![],
+                }
+            },
+            Source::Mp3(decoder) => match decoder.next_frame() {
+                Ok(frame) => from_i16(&frame.data),
+                Err(minimp3::Error::Eof) => vec![],
+                Err(e) => return Err(DiscoveryError::from(e)),
+            },
+        };
+        if block.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(block))
+        }
+    }
+
+    /**
+     * Decode the rest of the file
+     */
+    pub fn read_all(&mut self) -> Result<Vec<f32>> {
+        let mut samples = vec![];
+        while let Some(block) = self.next_block()? {
+            samples.extend(block);
+        }
+        Ok(samples)
+    }
+}
+
+fn from_i16(samples: &[i16]) -> Vec<f32> {
+    samples
+        .iter()
+        .map(|x| f32::from(*x) / full_scale(16))
+        .collect()
+}
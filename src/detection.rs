@@ -0,0 +1,476 @@
+use crate::error::*;
+use crate::features::*;
+use crate::numerics::*;
+use std::ops::Range;
+
+/**
+ * Scores how much each frame of a recording stands out from the noise.
+ * The activations are smoothed and compared to thresholds estimated from
+ * the activations of the same recording, so only their course within
+ * a recording matters, not their scale. Detectors may compare a frame
+ * with earlier ones, like the flux detector with the previous magnitudes,
+ * so they have to see all frames of a recording in order.
+ */
+pub trait Detector {
+    /// activation of the next frame given its magnitude spectrum
+    fn activation(&mut self, magnitude: &[f32]) -> f32;
+}
+
+/**
+ * The available detectors by name
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Detectors {
+    /// deviation of the feature frame
+    Variance,
+    /// log energy within the frequency band
+    Energy,
+    /// increase of the magnitudes within the frequency band
+    Flux,
+}
+
+/**
+ * Estimation of the thresholds on the smoothed activations
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Threshold {
+    /// percentiles of the whole recording
+    Global,
+    /// percentiles of a window of the given seconds around each frame
+    Running(f32),
+    /// median plus k times the median absolute deviation
+    /// of a window of the given seconds around each frame
+    Mad(f32),
+}
+
+/**
+ * Detector and thresholds.
+ * A region starts when the smoothed activation reaches the `on` threshold
+ * and ends when it falls below the `off` threshold.
+ */
+#[derive(Clone, Debug)]
+pub struct DetectionParams {
+    pub detector: Detectors,
+    /// lowest frequency of the energy and flux detectors in Hz
+    pub min_freq: f32,
+    /// highest frequency of the energy and flux detectors in Hz
+    pub max_freq: f32,
+    /// frames of the moving average over the activations
+    pub moving: usize,
+    pub threshold: Threshold,
+    pub on_percentile: f32,
+    /// at most the on percentile
+    pub off_percentile: f32,
+    /// number of deviations above the median of the on threshold
+    pub mad_on: f32,
+    /// number of deviations above the median of the off threshold, at most `mad_on`
+    pub mad_off: f32,
+    /// frames per second
+    pub frame_rate: f32,
+    /// regions of at most this many frames are dropped
+    pub min_len: usize,
+}
+
+impl DetectionParams {
+    /**
+     * Frequency band in Hz the detector looks at: the filterbank limits
+     * for the variance of the features, else the band of the detector
+     */
+    pub fn band(&self, features: &FeatureParams, sample_rate: u32) -> (f32, f32) {
+        let (min_freq, max_freq) = match self.detector {
+            Detectors::Variance => (features.filterbank.min_freq, features.filterbank.max_freq),
+            Detectors::Energy | Detectors::Flux => (self.min_freq, self.max_freq),
+        };
+        (min_freq, f32::min(max_freq, sample_rate as f32 / 2.0))
+    }
+
+    /**
+     * Detector without any previous frames, needed for each recording
+     * and channel since detectors remember the frames they have seen
+     */
+    pub fn detector(&self, features: &FeatureParams, sample_rate: u32) -> Box<dyn Detector> {
+        let band = frequency_bins(self.min_freq, self.max_freq, features.fft_size, sample_rate);
+        match self.detector {
+            Detectors::Variance => Box::new(VarianceDetector::new(features, sample_rate)),
+            Detectors::Energy => Box::new(EnergyDetector { band }),
+            Detectors::Flux => Box::new(FluxDetector {
+                band,
+                previous: vec![],
+            }),
+        }
+    }
+}
+
+/**
+ * Deviation of the normalized feature frame
+ */
+pub struct VarianceDetector {
+    extractor: Box<dyn FeatureExtractor>,
+    cmvn: RunningCmvn,
+}
+
+impl VarianceDetector {
+    pub fn new(features: &FeatureParams, sample_rate: u32) -> VarianceDetector {
+        VarianceDetector {
+            extractor: features.extractor(sample_rate),
+            cmvn: RunningCmvn::new(features.cmvn),
+        }
+    }
+}
+
+impl Detector for VarianceDetector {
+    fn activation(&mut self, magnitude: &[f32]) -> f32 {
+        let features = self.cmvn.apply(self.extractor.extract(magnitude));
+        std(&features, mean(&features))
+    }
+}
+
+/**
+ * Log energy of the bins within the frequency band
+ */
+pub struct EnergyDetector {
+    band: Range<usize>,
+}
+
+impl Detector for EnergyDetector {
+    fn activation(&mut self, magnitude: &[f32]) -> f32 {
+        let energy: f32 = magnitude[self.band.clone()].iter().map(|x| x * x).sum();
+        10.0 * f32::log10(energy + 1e-12)
+    }
+}
+
+/**
+ * Spectral flux: the summed increase of the magnitudes
+ * within the frequency band since the last frame.
+ * The band of the previous frame is kept, the first frame has no flux.
+ */
+pub struct FluxDetector {
+    band: Range<usize>,
+    previous: Vec<f32>,
+}
+
+impl Detector for FluxDetector {
+    fn activation(&mut self, magnitude: &[f32]) -> f32 {
+        let current = magnitude[self.band.clone()].to_vec();
+        let flux = self
+            .previous
+            .iter()
+            .zip(current.iter())
+            .map(|(previous, current)| f32::max(current - previous, 0.0))
+            .sum();
+        self.previous = current;
+        flux
+    }
+}
+
+/**
+ * What happens to segments longer than the maximum length
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Oversized {
+    /// cut into equal parts of at most the maximum length
+    Split,
+    /// drop the segment
+    Discard,
+}
+
+/**
+ * Post processing of the detected segments, all lengths in frames
+ */
+#[derive(Clone, Debug)]
+pub struct SegmentParams {
+    /// frames added before each segment
+    pub pre_padding: usize,
+    /// frames added after each segment
+    pub post_padding: usize,
+    /// segments separated by fewer frames are merged
+    pub merge_gap: usize,
+    /// longest segment, 0 for no limit
+    pub max_len: usize,
+    pub oversized: Oversized,
+}
+
+/**
+ * Merge detections separated by less than the gap, drop the ones of at most
+ * `min_len` frames, pad the rest within the recording of `n_frames` frames
+ * and split or discard the ones longer than the maximum length
+ */
+pub fn segments(
+    ranges: &[(usize, usize)],
+    params: &SegmentParams,
+    min_len: usize,
+    n_frames: usize,
+) -> Vec<(usize, usize)> {
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, stop) in ranges.iter().cloned() {
+        match merged.last_mut() {
+            Some(last) if start < last.1 + params.merge_gap => last.1 = usize::max(last.1, stop),
+            _ => merged.push((start, stop)),
+        }
+    }
+    let mut padded: Vec<(usize, usize)> = vec![];
+    let long = merged
+        .into_iter()
+        .filter(|(start, stop)| stop - start > min_len);
+    for (start, stop) in long {
+        let start = start.saturating_sub(params.pre_padding);
+        let stop = usize::min(stop + params.post_padding, n_frames);
+        // padding may make neighbours overlap
+        match padded.last_mut() {
+            Some(last) if start < last.1 => last.1 = usize::max(last.1, stop),
+            _ => padded.push((start, stop)),
+        }
+    }
+    if params.max_len == 0 {
+        return padded;
+    }
+    let mut segments = vec![];
+    for (start, stop) in padded {
+        let len = stop - start;
+        if len <= params.max_len {
+            segments.push((start, stop));
+        } else if let Oversized::Split = params.oversized {
+            let parts = len.div_ceil(params.max_len);
+            for part in 0..parts {
+                segments.push((start + part * len / parts, start + (part + 1) * len / parts));
+            }
+        }
+    }
+    segments
+}
+
+/**
+ * Smoothed activations of a recording and the on and off threshold of each frame
+ */
+pub struct Thresholds {
+    pub smoothed: Vec<f32>,
+    pub on: Vec<f32>,
+    pub off: Vec<f32>,
+}
+
+impl Thresholds {
+    pub fn new(activations: &[f32], params: &DetectionParams) -> Result<Thresholds> {
+        let smoothed = moving_avg(activations, params.moving);
+        let (on, off) = match params.threshold {
+            Threshold::Global => {
+                let on = percentile(&mut smoothed.clone(), params.on_percentile)?;
+                let off = percentile(&mut smoothed.clone(), params.off_percentile)?;
+                (vec![on; smoothed.len()], vec![off; smoothed.len()])
+            }
+            Threshold::Running(seconds) => {
+                let window = (seconds * params.frame_rate) as usize;
+                let on = running(&smoothed, window, |values| {
+                    percentile(values, params.on_percentile)
+                })?;
+                let off = running(&smoothed, window, |values| {
+                    percentile(values, params.off_percentile)
+                })?;
+                (on, off)
+            }
+            Threshold::Mad(seconds) => {
+                let window = (seconds * params.frame_rate) as usize;
+                let median = running(&smoothed, window, |values| percentile(values, 0.5))?;
+                let mad = running(&smoothed, window, |values| {
+                    let median = percentile(values, 0.5)?;
+                    let mut deviations: Vec<f32> =
+                        values.iter().map(|x| (x - median).abs()).collect();
+                    percentile(&mut deviations, 0.5)
+                })?;
+                let on = median
+                    .iter()
+                    .zip(&mad)
+                    .map(|(m, d)| m + params.mad_on * d)
+                    .collect();
+                let off = median
+                    .iter()
+                    .zip(&mad)
+                    .map(|(m, d)| m + params.mad_off * d)
+                    .collect();
+                (on, off)
+            }
+        };
+        let off = off
+            .iter()
+            .zip(&on)
+            .map(|(off, on)| f32::min(*off, *on))
+            .collect();
+        Ok(Thresholds { smoothed, on, off })
+    }
+
+    /**
+     * Ranges of frames that rise above the on threshold, until they fall below
+     * the off threshold, and that are longer than `min_len` frames.
+     * A range still open at the end is closed there.
+     */
+    pub fn ranges(&self, min_len: usize) -> Vec<(usize, usize)> {
+        let mut ranges = vec![];
+        let mut start = None;
+        for (i, activation) in self.smoothed.iter().enumerate() {
+            match start {
+                None if *activation >= self.on[i] => start = Some(i),
+                Some(first) if *activation < self.off[i] => {
+                    if i - first > min_len {
+                        ranges.push((first, i));
+                    }
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(first) = start {
+            if self.smoothed.len() - first > min_len {
+                ranges.push((first, self.smoothed.len()));
+            }
+        }
+        ranges
+    }
+}
+
+/**
+ * A statistic of a window of frames centered on each frame.
+ * The statistic is estimated every eighth of a window
+ * and linearly interpolated in between.
+ */
+fn running<F>(x: &[f32], window: usize, statistic: F) -> Result<Vec<f32>>
+where
+    F: Fn(&mut [f32]) -> Result<f32>,
+{
+    if x.is_empty() {
+        return Ok(vec![]);
+    }
+    let window = usize::max(window, 1);
+    let hop = usize::max(window / 8, 1);
+    let mut centers: Vec<usize> = (0..x.len()).step_by(hop).collect();
+    if centers.last() != Some(&(x.len() - 1)) {
+        centers.push(x.len() - 1);
+    }
+    let estimates = centers
+        .iter()
+        .map(|center| {
+            let start = center.saturating_sub(window / 2);
+            let stop = usize::min(center + window / 2 + 1, x.len());
+            statistic(&mut x[start..stop].to_vec())
+        })
+        .collect::<Result<Vec<f32>>>()?;
+    let mut curve = Vec::with_capacity(x.len());
+    for (i, pair) in centers.windows(2).enumerate() {
+        let (t0, t1) = (pair[0], pair[1]);
+        for t in t0..t1 {
+            let alpha = (t - t0) as f32 / (t1 - t0) as f32;
+            curve.push((1.0 - alpha) * estimates[i] + alpha * estimates[i + 1]);
+        }
+    }
+    curve.push(estimates[estimates.len() - 1]);
+    Ok(curve)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(moving: usize, on_percentile: f32, off_percentile: f32) -> DetectionParams {
+        DetectionParams {
+            detector: Detectors::Energy,
+            min_freq: 0.0,
+            max_freq: 0.0,
+            moving,
+            threshold: Threshold::Global,
+            on_percentile,
+            off_percentile,
+            mad_on: 0.0,
+            mad_off: 0.0,
+            frame_rate: 100.0,
+            min_len: 0,
+        }
+    }
+
+    #[test]
+    fn quiet_start_opens_no_range() {
+        let mut activations = vec![0.1; 30];
+        activations.extend(vec![1.0; 10]);
+        activations.extend(vec![0.1; 30]);
+        let params = params(1, 0.9, 0.9);
+        let ranges = Thresholds::new(&activations, &params)
+            .unwrap()
+            .ranges(params.min_len);
+        assert_eq!(ranges, vec![(31, 41)]);
+    }
+
+    #[test]
+    fn off_threshold_below_on_bridges_dips() {
+        let smoothed = vec![0.0, 0.0, 0.0, 5.0, 5.0, 2.0, 5.0, 5.0, 0.0, 0.0];
+        let thresholds = |off: f32| Thresholds {
+            on: vec![4.0; smoothed.len()],
+            off: vec![off; smoothed.len()],
+            smoothed: smoothed.clone(),
+        };
+        assert_eq!(thresholds(4.0).ranges(0), vec![(3, 5), (6, 8)]);
+        assert_eq!(thresholds(1.0).ranges(0), vec![(3, 8)]);
+    }
+
+    #[test]
+    fn range_open_at_the_end_is_kept() {
+        let thresholds = Thresholds {
+            smoothed: vec![0.0, 0.0, 5.0, 5.0],
+            on: vec![4.0; 4],
+            off: vec![1.0; 4],
+        };
+        assert_eq!(thresholds.ranges(0), vec![(2, 4)]);
+    }
+
+    fn segment_params(pre: usize, post: usize, gap: usize, max_len: usize) -> SegmentParams {
+        SegmentParams {
+            pre_padding: pre,
+            post_padding: post,
+            merge_gap: gap,
+            max_len,
+            oversized: Oversized::Split,
+        }
+    }
+
+    #[test]
+    fn segments_closer_than_the_gap_are_merged() {
+        let ranges = [(10, 20), (24, 30), (40, 50)];
+        assert_eq!(
+            segments(&ranges, &segment_params(0, 0, 5, 0), 0, 100),
+            vec![(10, 30), (40, 50)]
+        );
+        assert_eq!(
+            segments(&ranges, &segment_params(0, 0, 4, 0), 0, 100),
+            vec![(10, 20), (24, 30), (40, 50)]
+        );
+    }
+
+    #[test]
+    fn short_segments_are_dropped_before_padding() {
+        let ranges = [(10, 13), (40, 50)];
+        assert_eq!(
+            segments(&ranges, &segment_params(5, 5, 0, 0), 3, 100),
+            vec![(35, 55)]
+        );
+    }
+
+    #[test]
+    fn padding_is_clamped_to_the_recording_and_merges_overlaps() {
+        let ranges = [(2, 10), (14, 20), (90, 98)];
+        assert_eq!(
+            segments(&ranges, &segment_params(5, 5, 0, 0), 0, 100),
+            vec![(0, 25), (85, 100)]
+        );
+    }
+
+    #[test]
+    fn oversized_segments_are_split_equally_or_discarded() {
+        let ranges = [(0, 10), (20, 45)];
+        assert_eq!(
+            segments(&ranges, &segment_params(0, 0, 0, 10), 0, 100),
+            vec![(0, 10), (20, 28), (28, 36), (36, 45)]
+        );
+        let mut params = segment_params(0, 0, 0, 10);
+        params.oversized = Oversized::Discard;
+        assert_eq!(segments(&ranges, &params, 0, 100), vec![(0, 10)]);
+    }
+}
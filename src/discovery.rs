@@ -2,17 +2,77 @@ extern crate toml;
 use std::fs::File;
 use std::io::prelude::*;
 
-use crate::alignments::AlignmentParams;
+use crate::alignments::{AlignmentParams, FrequencyShift, Offset, ShiftParams};
+use crate::audio::{Channels, Filter, Normalization, PreprocessingParams};
+use crate::detection::{DetectionParams, Detectors, Oversized, SegmentParams, Threshold};
+use crate::error::*;
+use crate::features::{Cmvn, FeatureParams, Features, PcenParams, SpectrogramNorm};
+use crate::filterbank::{FilterbankParams, FrequencyScale};
+use crate::reporting::ClusterAudioParams;
+use crate::spectrogram::{ClusterInput, ContourParams, NoiseReduction, Window};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Discovery {
     pub dft_win: usize,
     pub dft_step: usize,
+    pub window: Window,
+    pub spec_min_freq: f32,
+    pub spec_max_freq: f32,
+    pub features: Features,
+    pub filterbank: FrequencyScale,
     pub ceps_filter: usize,
+    pub min_freq: f32,
+    pub max_freq: f32,
+    pub n_ceps: usize,
+    pub ceps_skip: usize,
+    pub lifter: usize,
+    pub pcen_alpha: f32,
+    pub pcen_delta: f32,
+    pub pcen_root: f32,
+    pub pcen_smoothing: f32,
+    pub cmvn: Cmvn,
+    pub spectrogram_norm: SpectrogramNorm,
+    pub delta_order: usize,
+    pub delta_window: usize,
+    pub channels: Channels,
+    pub extensions: Vec<String>,
+    pub sample_rate: u32,
+    pub dc_removal: bool,
+    pub pre_emphasis: f32,
+    pub filter: Filter,
+    pub filter_order: usize,
+    pub cluster_input: ClusterInput,
+    pub contour_min_freq: f32,
+    pub contour_max_freq: f32,
+    pub contour_max_jump: f32,
+    pub contour_prominence: f32,
+    pub noise_reduction: bool,
+    pub over_subtraction: f32,
+    pub noise_percentile: f32,
+    pub spectral_floor: f32,
+    pub cluster_gap: f32,
+    pub cluster_fade: f32,
+    pub cluster_normalization: Normalization,
+    pub griffin_lim_iterations: usize,
     pub vat_moving: usize,
     pub vat_percentile: f32,
+    pub vat_off_percentile: f32,
+    pub vat_threshold: Threshold,
+    pub vat_mad_on: f32,
+    pub vat_mad_off: f32,
+    pub detector: Detectors,
+    pub detector_min_freq: f32,
+    pub detector_max_freq: f32,
     pub vat_min_len: usize,
+    pub segment_pre_padding: f32,
+    pub segment_post_padding: f32,
+    pub segment_merge_gap: f32,
+    pub segment_max_len: f32,
+    pub segment_oversized: Oversized,
+    pub eval_iou: f32,
     pub alignment_workers: usize,
+    pub frequency_shift: FrequencyShift,
+    pub shift_step: f32,
     pub clustering_percentile: f32,
     pub warping_band_percentage: f32,
     pub insertion_penalty: f32,
@@ -22,17 +82,135 @@ pub struct Discovery {
     pub learning_rate: f32,
     pub epochs: usize,
     pub epoch_drop: f32,
-    pub drop: f32
+    pub drop: f32,
 }
 
 impl Discovery {
-    pub fn from_toml(file: String) -> Discovery {
+    pub fn from_toml(file: String) -> Result<Discovery> {
         let mut template_conf = String::new();
-        let _ = File::open(file)
-            .expect("Template file not found")
-            .read_to_string(&mut template_conf);
-        let conf: Discovery = toml::from_str(&template_conf).unwrap();
-        conf
+        File::open(file)?.read_to_string(&mut template_conf)?;
+        let conf: Discovery = toml::from_str(&template_conf)?;
+        conf.validate()?;
+        Ok(conf)
+    }
+
+    /**
+     * Reject values that would build a broken pipeline
+     */
+    fn validate(&self) -> Result<()> {
+        if self.dft_win == 0 || self.dft_win % 2 == 1 {
+            return Err(DiscoveryError::InvalidConfig(format!(
+                "dft_win = {} has to be even for the real fft",
+                self.dft_win
+            )));
+        }
+        if self.n_ceps == 0 || self.ceps_skip >= self.ceps_filter {
+            return Err(DiscoveryError::InvalidConfig(format!(
+                "n_ceps = {} and ceps_skip = {} keep no cepstral coefficients of {} filters",
+                self.n_ceps, self.ceps_skip, self.ceps_filter
+            )));
+        }
+        self.filter.validate(self.sample_rate)
+    }
+
+    pub fn noise_reduction(&self) -> Option<NoiseReduction> {
+        if self.noise_reduction {
+            Some(NoiseReduction {
+                over_subtraction: self.over_subtraction,
+                floor: self.spectral_floor,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn cluster_audio(&self) -> ClusterAudioParams {
+        ClusterAudioParams {
+            gap: self.cluster_gap,
+            fade: self.cluster_fade,
+            normalization: self.cluster_normalization,
+            griffin_lim_iterations: self.griffin_lim_iterations,
+        }
+    }
+
+    pub fn features(&self) -> FeatureParams {
+        FeatureParams {
+            fft_size: self.dft_win,
+            fft_step: self.dft_step,
+            window: self.window,
+            spec_min_freq: self.spec_min_freq,
+            spec_max_freq: self.spec_max_freq,
+            features: self.features,
+            filterbank: self.filterbank(),
+            pcen: PcenParams {
+                alpha: self.pcen_alpha,
+                delta: self.pcen_delta,
+                root: self.pcen_root,
+                smoothing: self.pcen_smoothing,
+            },
+            cmvn: self.cmvn,
+            spectrogram_norm: self.spectrogram_norm,
+            delta_order: self.delta_order,
+            delta_window: self.delta_window,
+        }
+    }
+
+    pub fn detection(&self) -> DetectionParams {
+        DetectionParams {
+            detector: self.detector,
+            min_freq: self.detector_min_freq,
+            max_freq: self.detector_max_freq,
+            moving: self.vat_moving,
+            threshold: self.vat_threshold,
+            on_percentile: self.vat_percentile,
+            off_percentile: self.vat_off_percentile,
+            mad_on: self.vat_mad_on,
+            mad_off: self.vat_mad_off,
+            frame_rate: self.sample_rate as f32 / self.dft_step as f32,
+            min_len: self.vat_min_len,
+        }
+    }
+
+    pub fn segments(&self) -> SegmentParams {
+        let frames =
+            |seconds: f32| (seconds * self.sample_rate as f32 / self.dft_step as f32) as usize;
+        SegmentParams {
+            pre_padding: frames(self.segment_pre_padding),
+            post_padding: frames(self.segment_post_padding),
+            merge_gap: frames(self.segment_merge_gap),
+            max_len: frames(self.segment_max_len),
+            oversized: self.segment_oversized,
+        }
+    }
+
+    pub fn contour(&self) -> ContourParams {
+        ContourParams {
+            min_freq: self.contour_min_freq,
+            max_freq: self.contour_max_freq,
+            max_jump: self.contour_max_jump,
+            prominence: self.contour_prominence,
+        }
+    }
+
+    pub fn filterbank(&self) -> FilterbankParams {
+        FilterbankParams {
+            scale: self.filterbank,
+            n_filters: self.ceps_filter,
+            min_freq: self.min_freq,
+            max_freq: self.max_freq,
+            n_ceps: self.n_ceps,
+            ceps_skip: self.ceps_skip,
+            lifter: self.lifter,
+        }
+    }
+
+    pub fn preprocessing(&self) -> PreprocessingParams {
+        PreprocessingParams {
+            dc_removal: self.dc_removal,
+            pre_emphasis: self.pre_emphasis,
+            filter: self.filter,
+            filter_order: self.filter_order,
+        }
     }
 
     pub fn alignment_params(&self, n_size: usize) -> AlignmentParams {
@@ -41,6 +219,16 @@ impl Discovery {
             insertion_penalty: self.insertion_penalty,
             match_penalty: self.match_penalty,
             deletion_penalty: self.deletion_penalty,
+            offset: Offset::None,
+        }
+    }
+
+    pub fn shift_params(&self) -> ShiftParams {
+        ShiftParams {
+            input: self.cluster_input,
+            shift: self.frequency_shift,
+            step: self.shift_step,
+            bin_hz: self.sample_rate as f32 / self.dft_win as f32,
         }
     }
 }
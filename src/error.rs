@@ -1,8 +1,32 @@
+use std::fmt;
 use std::io::*;
 
 #[derive(Debug)]
 pub enum DiscoveryError {
     IO(Error),
+    /// Audio that can not be decoded or encoded
+    Audio(String),
+    /// A config file that can not be parsed
+    Config(toml::de::Error),
+    /// A config value that is out of range
+    InvalidConfig(String),
+    /// A model that can not be serialized or deserialized
+    Model(bincode::Error),
+    /// An operation that needs data got none
+    EmptyData(String),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiscoveryError::IO(e) => write!(f, "IO error: {}", e),
+            DiscoveryError::Audio(e) => write!(f, "Audio error: {}", e),
+            DiscoveryError::Config(e) => write!(f, "Config error: {}", e),
+            DiscoveryError::InvalidConfig(e) => write!(f, "Invalid config: {}", e),
+            DiscoveryError::Model(e) => write!(f, "Model error: {}", e),
+            DiscoveryError::EmptyData(e) => write!(f, "Empty data: {}", e),
+        }
+    }
 }
 
 impl From<Error> for DiscoveryError {
@@ -11,4 +35,40 @@ impl From<Error> for DiscoveryError {
     }
 }
 
+impl From<hound::Error> for DiscoveryError {
+    fn from(e: hound::Error) -> DiscoveryError {
+        DiscoveryError::Audio(format!("wav: {}", e))
+    }
+}
+
+impl From<claxon::Error> for DiscoveryError {
+    fn from(e: claxon::Error) -> DiscoveryError {
+        DiscoveryError::Audio(format!("flac: {}", e))
+    }
+}
+
+impl From<lewton::VorbisError> for DiscoveryError {
+    fn from(e: lewton::VorbisError) -> DiscoveryError {
+        DiscoveryError::Audio(format!("ogg: {}", e))
+    }
+}
+
+impl From<minimp3::Error> for DiscoveryError {
+    fn from(e: minimp3::Error) -> DiscoveryError {
+        DiscoveryError::Audio(format!("mp3: {}", e))
+    }
+}
+
+impl From<toml::de::Error> for DiscoveryError {
+    fn from(e: toml::de::Error) -> DiscoveryError {
+        DiscoveryError::Config(e)
+    }
+}
+
+impl From<bincode::Error> for DiscoveryError {
+    fn from(e: bincode::Error) -> DiscoveryError {
+        DiscoveryError::Model(e)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, DiscoveryError>;
@@ -0,0 +1,336 @@
+use crate::error::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/**
+ * An event between two times in seconds, on one channel if known
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    pub start: f32,
+    pub stop: f32,
+    /// channel index counted from 0, none for all channels
+    pub channel: Option<usize>,
+}
+
+impl Event {
+    /**
+     * Events can only match on the same channel,
+     * an event without a channel matches any channel
+     */
+    pub fn same_channel(&self, other: &Event) -> bool {
+        match (self.channel, other.channel) {
+            (Some(x), Some(y)) => x == y,
+            _ => true,
+        }
+    }
+
+    /**
+     * Temporal intersection over union of two events
+     */
+    pub fn iou(&self, other: &Event) -> f32 {
+        let intersection = f32::min(self.stop, other.stop) - f32::max(self.start, other.start);
+        let union = f32::max(self.stop, other.stop) - f32::min(self.start, other.start);
+        if intersection <= 0.0 || union <= 0.0 {
+            0.0
+        } else {
+            intersection / union
+        }
+    }
+}
+
+/**
+ * Reference annotations of an audio file: a Raven selection table
+ * or an Audacity label track next to it with the same name
+ */
+pub fn reference_file(audio_file: &str) -> Option<String> {
+    let path = Path::new(audio_file);
+    let stem = path.file_stem()?.to_string_lossy().to_string();
+    let folder = path.parent()?;
+    [
+        format!("{}.selections.txt", stem),
+        format!("{}.Table.1.selections.txt", stem),
+        format!("{}.labels.txt", stem),
+        format!("{}.txt", stem),
+    ]
+    .iter()
+    .map(|name| folder.join(name))
+    .find(|candidate| candidate.exists())
+    .map(|candidate| candidate.to_string_lossy().to_string())
+}
+
+/**
+ * Read the events of a Raven selection table or an Audacity label track.
+ * Raven rows of the same selection in several views are read once,
+ * Audacity frequency lines are skipped.
+ */
+pub fn read_events(file: &str) -> Result<Vec<Event>> {
+    let mut content = String::new();
+    File::open(file)?.read_to_string(&mut content)?;
+    parse_events(&content)
+        .ok_or_else(|| DiscoveryError::EmptyData(format!("no begin and end time in {}", file)))
+}
+
+/**
+ * Events of the content of a selection table or label track,
+ * none for a selection table without begin and end time.
+ * Raven channels are counted from 1, Audacity labels have no channel.
+ */
+fn parse_events(content: &str) -> Option<Vec<Event>> {
+    let mut lines = content.lines().peekable();
+    let header: Vec<&str> = match lines.peek() {
+        Some(line) if line.contains("Begin Time (s)") => line.split('\t').collect(),
+        _ => vec![],
+    };
+    let mut events = vec![];
+    if header.is_empty() {
+        for line in lines.filter(|line| !line.starts_with('\\')) {
+            let cols: Vec<&str> = line.split('\t').collect();
+            if cols.len() >= 2 {
+                if let (Ok(start), Ok(stop)) = (cols[0].trim().parse(), cols[1].trim().parse()) {
+                    events.push(Event {
+                        start,
+                        stop,
+                        channel: None,
+                    });
+                }
+            }
+        }
+    } else {
+        let column = |name: &str| header.iter().position(|col| col.trim() == name);
+        let begin = column("Begin Time (s)")?;
+        let end = column("End Time (s)")?;
+        let selection = column("Selection");
+        let channel = column("Channel");
+        let mut seen = HashSet::new();
+        for line in lines.skip(1) {
+            let cols: Vec<&str> = line.split('\t').collect();
+            if let Some(id) = selection.and_then(|s| cols.get(s)) {
+                if !seen.insert(id.to_string()) {
+                    continue;
+                }
+            }
+            let channel = channel
+                .and_then(|c| cols.get(c))
+                .and_then(|c| c.trim().parse::<usize>().ok())
+                .map(|c| c.saturating_sub(1));
+            if let (Some(start), Some(stop)) = (cols.get(begin), cols.get(end)) {
+                if let (Ok(start), Ok(stop)) = (start.trim().parse(), stop.trim().parse()) {
+                    events.push(Event {
+                        start,
+                        stop,
+                        channel,
+                    });
+                }
+            }
+        }
+    }
+    Some(events)
+}
+
+/**
+ * Merge overlapping events of all channels into events without a channel,
+ * so a call detected on several channels counts once
+ */
+pub fn merge_channels(events: &[Event]) -> Vec<Event> {
+    let mut sorted = events.to_vec();
+    sorted.sort_by(|a, b| {
+        a.start
+            .partial_cmp(&b.start)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut merged: Vec<Event> = vec![];
+    for event in sorted {
+        match merged.last_mut() {
+            Some(last) if event.start < last.stop => last.stop = f32::max(last.stop, event.stop),
+            _ => merged.push(Event {
+                channel: None,
+                ..event
+            }),
+        }
+    }
+    merged
+}
+
+/**
+ * Detections matched to reference events.
+ * Pairs on the same channel are matched greedily by decreasing temporal IoU,
+ * each detection and each reference at most once.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Evaluation {
+    pub detections: usize,
+    pub references: usize,
+    pub matches: usize,
+    /// summed absolute start difference of the matches in seconds
+    pub start_error: f32,
+    /// summed absolute stop difference of the matches in seconds
+    pub stop_error: f32,
+}
+
+impl Evaluation {
+    pub fn new(detections: &[Event], references: &[Event], min_iou: f32) -> Evaluation {
+        let mut pairs = vec![];
+        for (i, detection) in detections.iter().enumerate() {
+            for (j, reference) in references.iter().enumerate() {
+                if !detection.same_channel(reference) {
+                    continue;
+                }
+                let iou = detection.iou(reference);
+                if iou > 0.0 && iou >= min_iou {
+                    pairs.push((iou, i, j));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut evaluation = Evaluation {
+            detections: detections.len(),
+            references: references.len(),
+            ..Evaluation::default()
+        };
+        let mut matched_detections = HashSet::new();
+        let mut matched_references = HashSet::new();
+        for (_, i, j) in pairs {
+            if !matched_detections.contains(&i) && !matched_references.contains(&j) {
+                matched_detections.insert(i);
+                matched_references.insert(j);
+                evaluation.matches += 1;
+                evaluation.start_error += (detections[i].start - references[j].start).abs();
+                evaluation.stop_error += (detections[i].stop - references[j].stop).abs();
+            }
+        }
+        evaluation
+    }
+
+    /**
+     * Accumulate the counts of another evaluation
+     */
+    pub fn add(&mut self, other: &Evaluation) {
+        self.detections += other.detections;
+        self.references += other.references;
+        self.matches += other.matches;
+        self.start_error += other.start_error;
+        self.stop_error += other.stop_error;
+    }
+
+    fn ratio(x: f32, n: usize) -> f32 {
+        if n == 0 {
+            0.0
+        } else {
+            x / n as f32
+        }
+    }
+
+    pub fn precision(&self) -> f32 {
+        Evaluation::ratio(self.matches as f32, self.detections)
+    }
+
+    pub fn recall(&self) -> f32 {
+        Evaluation::ratio(self.matches as f32, self.references)
+    }
+
+    pub fn f1(&self) -> f32 {
+        let (precision, recall) = (self.precision(), self.recall());
+        if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        }
+    }
+
+    /**
+     * Mean absolute start and stop difference of the matches in seconds
+     */
+    pub fn boundary_error(&self) -> (f32, f32) {
+        (
+            Evaluation::ratio(self.start_error, self.matches),
+            Evaluation::ratio(self.stop_error, self.matches),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(start: f32, stop: f32, channel: Option<usize>) -> Event {
+        Event {
+            start,
+            stop,
+            channel,
+        }
+    }
+
+    #[test]
+    fn iou_edge_cases() {
+        let x = event(1.0, 3.0, None);
+        assert_eq!(x.iou(&x), 1.0);
+        assert_eq!(x.iou(&event(1.5, 2.5, None)), 0.5);
+        assert_eq!(x.iou(&event(2.0, 4.0, None)), 1.0 / 3.0);
+        // touching and disjoint events do not overlap
+        assert_eq!(x.iou(&event(3.0, 4.0, None)), 0.0);
+        assert_eq!(x.iou(&event(5.0, 6.0, None)), 0.0);
+        // an empty event overlaps nothing, not even itself
+        let empty = event(2.0, 2.0, None);
+        assert_eq!(empty.iou(&empty), 0.0);
+        assert_eq!(x.iou(&empty), 0.0);
+    }
+
+    #[test]
+    fn raven_views_of_a_selection_are_read_once() {
+        let table = "Selection\tView\tChannel\tBegin Time (s)\tEnd Time (s)\tLow Freq (Hz)\n\
+                     1\tWaveform 1\t1\t4.0\t4.8\t1000\n\
+                     1\tSpectrogram 1\t1\t4.0\t4.8\t1000\n\
+                     2\tWaveform 1\t2\t14.0\t14.5\t1000\n\
+                     2\tSpectrogram 1\t2\t14.0\t14.5\t1000\n";
+        assert_eq!(
+            parse_events(table).unwrap(),
+            vec![event(4.0, 4.8, Some(0)), event(14.0, 14.5, Some(1))]
+        );
+        assert_eq!(parse_events("Selection\tBegin Time (s)\n1\t4.0\n"), None);
+    }
+
+    #[test]
+    fn audacity_frequency_lines_are_skipped() {
+        let labels = "4.0\t4.8\twhistle\n\\\t1000\t20000\n14.0\t14.5\n\\\t500\t8000\n";
+        assert_eq!(
+            parse_events(labels).unwrap(),
+            vec![event(4.0, 4.8, None), event(14.0, 14.5, None)]
+        );
+    }
+
+    #[test]
+    fn greedy_matching_prefers_the_highest_iou() {
+        let references = [event(0.0, 1.0, None), event(1.0, 2.0, None)];
+        // the first detection overlaps both references, the second only the second one
+        let detections = [event(0.4, 1.6, None), event(1.0, 1.9, None)];
+        let evaluation = Evaluation::new(&detections, &references, 0.3);
+        assert_eq!(evaluation.matches, 2);
+        assert_eq!(evaluation.precision(), 1.0);
+        assert_eq!(evaluation.recall(), 1.0);
+        let (start_error, stop_error) = evaluation.boundary_error();
+        assert!((start_error - 0.2).abs() < 1e-6);
+        assert!((stop_error - 0.35).abs() < 1e-6);
+        assert_eq!(Evaluation::new(&detections, &references, 0.95).matches, 0);
+    }
+
+    #[test]
+    fn detections_on_several_channels_count_once() {
+        let detections = [
+            event(4.0, 4.8, Some(0)),
+            event(4.1, 4.9, Some(1)),
+            event(9.0, 9.5, Some(1)),
+        ];
+        // references with channels match on their channel only
+        let references = [event(4.0, 4.8, Some(0)), event(9.0, 9.5, Some(1))];
+        let evaluation = Evaluation::new(&detections, &references, 0.5);
+        assert_eq!((evaluation.matches, evaluation.detections), (2, 3));
+        // references without channels match the merged detections
+        let merged = merge_channels(&detections);
+        assert_eq!(merged, vec![event(4.0, 4.9, None), event(9.0, 9.5, None)]);
+        let references = [event(4.0, 4.8, None), event(9.0, 9.5, None)];
+        assert_eq!(Evaluation::new(&merged, &references, 0.5).f1(), 1.0);
+    }
+}
@@ -0,0 +1,499 @@
+use crate::filterbank::*;
+use crate::numerics::*;
+use crate::spectrogram::Window;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/**
+ * Turns the magnitude spectrum of each frame into a feature vector.
+ * Frames of one recording are passed in order, so extractors
+ * may keep state between frames.
+ */
+pub trait FeatureExtractor {
+    /// number of features per frame
+    fn n_features(&self) -> usize;
+
+    /// features of the next frame
+    fn extract(&mut self, magnitude: &[f32]) -> Vec<f32>;
+}
+
+/**
+ * The available feature extractors by name
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Features {
+    Cepstrum,
+    LogSpectrogram,
+    Mfcc,
+    Pcen,
+}
+
+/**
+ * Cepstral mean and variance normalization of the feature frames
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Cmvn {
+    /// features as extracted
+    None,
+    /// subtract the mean of each frame
+    Frame,
+    /// standard score of each feature over the whole sequence
+    Utterance,
+    /// standard score of each feature over a window of frames
+    Sliding(usize),
+}
+
+/**
+ * Normalization of the spectrogram frames
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectrogramNorm {
+    /// standard score of each frame
+    Frame,
+    /// per channel energy normalization of each bin
+    Pcen,
+}
+
+/**
+ * Parameters of per channel energy normalization
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct PcenParams {
+    /// gain normalization exponent
+    pub alpha: f32,
+    /// bias added before compression
+    pub delta: f32,
+    /// root compression exponent
+    pub root: f32,
+    /// weight of the current frame in the smoothed energy
+    pub smoothing: f32,
+}
+
+/**
+ * Framing and feature extraction parameters
+ */
+#[derive(Clone, Debug)]
+pub struct FeatureParams {
+    pub fft_size: usize,
+    pub fft_step: usize,
+    pub window: Window,
+    /// lowest frequency of the spectrogram in Hz
+    pub spec_min_freq: f32,
+    /// highest frequency of the spectrogram in Hz, at most nyquist
+    pub spec_max_freq: f32,
+    pub features: Features,
+    pub filterbank: FilterbankParams,
+    pub pcen: PcenParams,
+    pub cmvn: Cmvn,
+    pub spectrogram_norm: SpectrogramNorm,
+    /// 0 for static features only, 1 to add deltas, 2 to add delta deltas
+    pub delta_order: usize,
+    /// frames on each side of the delta regression
+    pub delta_window: usize,
+}
+
+impl FeatureParams {
+    /**
+     * Bins of the magnitude spectrum within the spectrogram crop limits
+     */
+    pub fn spectrogram_bins(&self, sample_rate: u32) -> Range<usize> {
+        frequency_bins(
+            self.spec_min_freq,
+            self.spec_max_freq,
+            self.fft_size,
+            sample_rate,
+        )
+    }
+
+    /**
+     * A fresh extractor for one recording
+     */
+    pub fn extractor(&self, sample_rate: u32) -> Box<dyn FeatureExtractor> {
+        match self.features {
+            Features::Cepstrum => Box::new(Cepstrum::new(
+                Filterbank::new(&self.filterbank, self.fft_size, sample_rate),
+                true,
+            )),
+            Features::Mfcc => {
+                let mut filterbank = self.filterbank.clone();
+                filterbank.scale = FrequencyScale::Mel;
+                Box::new(Cepstrum::new(
+                    Filterbank::new(&filterbank, self.fft_size, sample_rate),
+                    false,
+                ))
+            }
+            Features::LogSpectrogram => Box::new(LogSpectrogram::new(
+                &self.filterbank,
+                self.fft_size,
+                sample_rate,
+            )),
+            Features::Pcen => Box::new(Pcen::new(
+                Filterbank::new(&self.filterbank, self.fft_size, sample_rate),
+                self.pcen,
+            )),
+        }
+    }
+}
+
+/**
+ * Bins of a magnitude spectrum between two frequencies in Hz,
+ * at least one bin below nyquist
+ */
+pub fn frequency_bins(
+    min_freq: f32,
+    max_freq: f32,
+    fft_size: usize,
+    sample_rate: u32,
+) -> Range<usize> {
+    let bin_hz = sample_rate as f32 / fft_size as f32;
+    let last = usize::min((max_freq / bin_hz) as usize, fft_size / 2 - 1);
+    let first = usize::min((min_freq / bin_hz).ceil() as usize, last);
+    first..last + 1
+}
+
+/**
+ * Regression deltas of flat frames over `window` frames on each side.
+ * Frames beyond the edges repeat the first and last frame.
+ */
+pub fn deltas(frames: &[f32], n_bins: usize, window: usize) -> Vec<f32> {
+    let n = frames.len() / n_bins;
+    let norm = 2.0 * (1..=window).map(|k| (k * k) as f32).sum::<f32>();
+    let mut deltas = vec![0.0; frames.len()];
+    for t in 0..n {
+        for k in 1..=window {
+            let next = usize::min(t + k, n - 1);
+            let prev = t.saturating_sub(k);
+            for d in 0..n_bins {
+                deltas[t * n_bins + d] +=
+                    k as f32 * (frames[next * n_bins + d] - frames[prev * n_bins + d]) / norm;
+            }
+        }
+    }
+    deltas
+}
+
+/**
+ * Append deltas up to the given order to each frame.
+ * Returns the new frames and the number of features per frame.
+ */
+pub fn with_deltas(
+    frames: Vec<f32>,
+    n_bins: usize,
+    order: usize,
+    window: usize,
+) -> (Vec<f32>, usize) {
+    if order == 0 || window == 0 || n_bins == 0 {
+        return (frames, n_bins);
+    }
+    let mut orders = vec![frames];
+    for i in 0..order {
+        let delta = deltas(&orders[i], n_bins, window);
+        orders.push(delta);
+    }
+    let n = orders[0].len() / n_bins;
+    let mut extended = Vec::with_capacity(n * n_bins * orders.len());
+    for t in 0..n {
+        for features in orders.iter() {
+            extended.extend_from_slice(&features[t * n_bins..(t + 1) * n_bins]);
+        }
+    }
+    (extended, n_bins * orders.len())
+}
+
+/**
+ * Filterbank cepstrum, optionally normalised to zero mean in each frame
+ */
+pub struct Cepstrum {
+    filterbank: Filterbank,
+    zero_mean: bool,
+}
+
+impl Cepstrum {
+    pub fn new(filterbank: Filterbank, zero_mean: bool) -> Cepstrum {
+        Cepstrum {
+            filterbank,
+            zero_mean,
+        }
+    }
+}
+
+impl FeatureExtractor for Cepstrum {
+    fn n_features(&self) -> usize {
+        self.filterbank.n_ceps()
+    }
+
+    fn extract(&mut self, magnitude: &[f32]) -> Vec<f32> {
+        let cepstrum = self.filterbank.cepstrum(magnitude);
+        if self.zero_mean {
+            let mu = mean(&cepstrum);
+            cepstrum.iter().map(|c| c - mu).collect()
+        } else {
+            cepstrum
+        }
+    }
+}
+
+/**
+ * Log magnitude of the bins between the frequency limits of the filterbank
+ */
+pub struct LogSpectrogram {
+    first: usize,
+    last: usize,
+}
+
+impl LogSpectrogram {
+    pub fn new(params: &FilterbankParams, fft_size: usize, sample_rate: u32) -> LogSpectrogram {
+        let bins = frequency_bins(params.min_freq, params.max_freq, fft_size, sample_rate);
+        LogSpectrogram {
+            first: bins.start,
+            last: bins.end - 1,
+        }
+    }
+}
+
+impl FeatureExtractor for LogSpectrogram {
+    fn n_features(&self) -> usize {
+        self.last - self.first + 1
+    }
+
+    fn extract(&mut self, magnitude: &[f32]) -> Vec<f32> {
+        magnitude[self.first..=self.last]
+            .iter()
+            .map(|x| f32::ln(x + 1e-6))
+            .collect()
+    }
+}
+
+/**
+ * Per channel energy normalization [Wang et al. 2017].
+ * The smoothed energy of each channel is the automatic gain control.
+ */
+pub struct PcenState {
+    params: PcenParams,
+    smoothed: Vec<f32>,
+}
+
+impl PcenState {
+    pub fn new(params: PcenParams) -> PcenState {
+        PcenState {
+            params,
+            smoothed: vec![],
+        }
+    }
+
+    /**
+     * Normalize the energies of the next frame
+     */
+    pub fn apply(&mut self, energies: &[f32]) -> Vec<f32> {
+        if self.smoothed.is_empty() {
+            self.smoothed = energies.to_vec();
+        }
+        let PcenParams {
+            alpha,
+            delta,
+            root,
+            smoothing,
+        } = self.params;
+        let offset = f32::powf(delta, root);
+        energies
+            .iter()
+            .zip(self.smoothed.iter_mut())
+            .map(|(e, m)| {
+                *m = (1.0 - smoothing) * *m + smoothing * e;
+                let gain = f32::powf(1e-6 + *m, alpha);
+                f32::powf(e / gain + delta, root) - offset
+            })
+            .collect()
+    }
+}
+
+/**
+ * Per channel energy normalization of the filterbank energies
+ */
+pub struct Pcen {
+    filterbank: Filterbank,
+    state: PcenState,
+}
+
+impl Pcen {
+    pub fn new(filterbank: Filterbank, params: PcenParams) -> Pcen {
+        Pcen {
+            filterbank,
+            state: PcenState::new(params),
+        }
+    }
+}
+
+impl FeatureExtractor for Pcen {
+    fn n_features(&self) -> usize {
+        self.filterbank.n_filters()
+    }
+
+    fn extract(&mut self, magnitude: &[f32]) -> Vec<f32> {
+        self.state.apply(&self.filterbank.apply(magnitude))
+    }
+}
+
+/**
+ * Normalize flat frames of a whole sequence
+ */
+pub fn cmvn(frames: &[f32], n_bins: usize, cmvn: Cmvn) -> Vec<f32> {
+    if n_bins == 0 {
+        return frames.to_vec();
+    }
+    let n = frames.len() / n_bins;
+    match cmvn {
+        Cmvn::None => frames.to_vec(),
+        Cmvn::Frame => frames
+            .chunks(n_bins)
+            .flat_map(|frame| {
+                let mu = mean(frame);
+                frame.iter().map(move |x| x - mu)
+            })
+            .collect(),
+        Cmvn::Utterance => standard_score(frames, n_bins, |_| (0, n)),
+        Cmvn::Sliding(window) => standard_score(frames, n_bins, |t| {
+            (
+                t.saturating_sub(window / 2),
+                usize::min(t + window / 2 + 1, n),
+            )
+        }),
+    }
+}
+
+/**
+ * Standard score of each feature over the frames in the range of each frame.
+ * The statistics of a range come from running sums over the frames,
+ * so each frame costs the same however long the range is.
+ */
+fn standard_score<F: Fn(usize) -> (usize, usize)>(
+    frames: &[f32],
+    n_bins: usize,
+    range: F,
+) -> Vec<f32> {
+    let n = frames.len() / n_bins;
+    let mut sum = vec![0.0f64; (n + 1) * n_bins];
+    let mut sum_sq = vec![0.0f64; (n + 1) * n_bins];
+    for t in 0..n {
+        for d in 0..n_bins {
+            let x = f64::from(frames[t * n_bins + d]);
+            sum[(t + 1) * n_bins + d] = sum[t * n_bins + d] + x;
+            sum_sq[(t + 1) * n_bins + d] = sum_sq[t * n_bins + d] + x * x;
+        }
+    }
+    let mut normalized = vec![0.0; frames.len()];
+    for t in 0..n {
+        let (start, stop) = range(t);
+        let k = (stop - start) as f64;
+        for d in 0..n_bins {
+            let mu = (sum[stop * n_bins + d] - sum[start * n_bins + d]) / k;
+            let sq = (sum_sq[stop * n_bins + d] - sum_sq[start * n_bins + d]) / k;
+            let sigma = f64::max(f64::sqrt(f64::max(sq - mu * mu, 0.0)), 1e-6);
+            normalized[t * n_bins + d] = ((f64::from(frames[t * n_bins + d]) - mu) / sigma) as f32;
+        }
+    }
+    normalized
+}
+
+/**
+ * Normalize a stream of frames, using only frames seen so far.
+ * Utterance normalization uses the running statistics of all frames,
+ * sliding normalization the statistics of the last frames of the window.
+ */
+pub struct RunningCmvn {
+    cmvn: Cmvn,
+    history: VecDeque<Vec<f32>>,
+    sum: Vec<f64>,
+    sum_sq: Vec<f64>,
+    n: usize,
+}
+
+impl RunningCmvn {
+    pub fn new(cmvn: Cmvn) -> RunningCmvn {
+        RunningCmvn {
+            cmvn,
+            history: VecDeque::new(),
+            sum: vec![],
+            sum_sq: vec![],
+            n: 0,
+        }
+    }
+
+    /**
+     * Normalize the next frame
+     */
+    pub fn apply(&mut self, frame: Vec<f32>) -> Vec<f32> {
+        match self.cmvn {
+            Cmvn::None => return frame,
+            Cmvn::Frame => return cmvn(&frame, frame.len(), Cmvn::Frame),
+            _ => (),
+        }
+        if self.sum.is_empty() {
+            self.sum = vec![0.0; frame.len()];
+            self.sum_sq = vec![0.0; frame.len()];
+        }
+        for (d, x) in frame.iter().enumerate() {
+            self.sum[d] += f64::from(*x);
+            self.sum_sq[d] += f64::from(*x) * f64::from(*x);
+        }
+        self.n += 1;
+        if let Cmvn::Sliding(window) = self.cmvn {
+            self.history.push_back(frame.clone());
+            if self.history.len() > usize::max(window, 1) {
+                if let Some(old) = self.history.pop_front() {
+                    for (d, x) in old.iter().enumerate() {
+                        self.sum[d] -= f64::from(*x);
+                        self.sum_sq[d] -= f64::from(*x) * f64::from(*x);
+                    }
+                    self.n -= 1;
+                }
+            }
+        }
+        let n = self.n as f64;
+        frame
+            .iter()
+            .enumerate()
+            .map(|(d, x)| {
+                let mu = self.sum[d] / n;
+                let var = f64::max(self.sum_sq[d] / n - mu * mu, 0.0);
+                ((f64::from(*x) - mu) / f64::max(var.sqrt(), 1e-6)) as f32
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// standard score of one feature over the frames of a range, computed directly
+    fn direct(frames: &[f32], n_bins: usize, d: usize, t: usize, start: usize, stop: usize) -> f32 {
+        let values: Vec<f32> = (start..stop).map(|i| frames[i * n_bins + d]).collect();
+        let mu = mean(&values);
+        let sigma = f32::max(std(&values, mu), 1e-6);
+        (frames[t * n_bins + d] - mu) / sigma
+    }
+
+    #[test]
+    fn running_sums_match_direct_standard_score() {
+        let n_bins = 3;
+        let frames: Vec<f32> = (0..40 * n_bins)
+            .map(|i| f32::sin(i as f32 * 0.7) * (i % 5) as f32)
+            .collect();
+        let n = frames.len() / n_bins;
+        let utterance = cmvn(&frames, n_bins, Cmvn::Utterance);
+        let sliding = cmvn(&frames, n_bins, Cmvn::Sliding(7));
+        for t in 0..n {
+            for d in 0..n_bins {
+                let expected = direct(&frames, n_bins, d, t, 0, n);
+                assert!((utterance[t * n_bins + d] - expected).abs() < 1e-4);
+                let (start, stop) = (t.saturating_sub(3), usize::min(t + 4, n));
+                let expected = direct(&frames, n_bins, d, t, start, stop);
+                assert!((sliding[t * n_bins + d] - expected).abs() < 1e-4);
+            }
+        }
+    }
+}
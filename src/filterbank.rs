@@ -0,0 +1,181 @@
+use rustdct::{DCTplanner, Type2And3};
+use std::sync::Arc;
+
+/**
+ * Spacing of the filter centers
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FrequencyScale {
+    Mel,
+    Linear,
+    Erb,
+}
+
+impl FrequencyScale {
+    pub fn from_hz(&self, hz: f32) -> f32 {
+        match self {
+            FrequencyScale::Mel => 2595.0 * f32::log10(1.0 + hz / 700.0),
+            FrequencyScale::Linear => hz,
+            FrequencyScale::Erb => 21.4 * f32::log10(1.0 + 0.00437 * hz),
+        }
+    }
+
+    pub fn to_hz(&self, x: f32) -> f32 {
+        match self {
+            FrequencyScale::Mel => 700.0 * (f32::powf(10.0, x / 2595.0) - 1.0),
+            FrequencyScale::Linear => x,
+            FrequencyScale::Erb => (f32::powf(10.0, x / 21.4) - 1.0) / 0.00437,
+        }
+    }
+}
+
+/**
+ * Parameters of the filterbank cepstrum
+ */
+#[derive(Clone, Debug)]
+pub struct FilterbankParams {
+    pub scale: FrequencyScale,
+    /// number of triangular filters
+    pub n_filters: usize,
+    /// lower edge of the first filter in Hz
+    pub min_freq: f32,
+    /// upper edge of the last filter in Hz, at most nyquist
+    pub max_freq: f32,
+    /// number of cepstral coefficients kept
+    pub n_ceps: usize,
+    /// number of leading cepstral coefficients dropped
+    pub ceps_skip: usize,
+    /// sinusoidal lifter parameter, zero disables liftering
+    pub lifter: usize,
+}
+
+/**
+ * Triangular filters over the bins of a magnitude spectrum
+ * followed by log and dct.
+ */
+pub struct Filterbank {
+    /// first bin and weights of each filter
+    filters: Vec<(usize, Vec<f32>)>,
+    lifter: Vec<f32>,
+    skip: usize,
+    dct: Arc<dyn Type2And3<f32>>,
+    /// scratch buffers of the dct
+    dct_input: Vec<f32>,
+    dct_output: Vec<f32>,
+}
+
+impl Filterbank {
+    pub fn new(params: &FilterbankParams, fft_size: usize, sample_rate: u32) -> Filterbank {
+        let n_bins = fft_size / 2;
+        let nyquist = sample_rate as f32 / 2.0;
+        let bin_hz = sample_rate as f32 / fft_size as f32;
+        let max_freq = f32::min(params.max_freq, nyquist);
+        let min_freq = f32::min(params.min_freq, max_freq);
+        let low = params.scale.from_hz(min_freq);
+        let high = params.scale.from_hz(max_freq);
+        let edges: Vec<f32> = (0..params.n_filters + 2)
+            .map(|i| {
+                let x = low + (high - low) * i as f32 / (params.n_filters + 1) as f32;
+                params.scale.to_hz(x) / bin_hz
+            })
+            .collect();
+        let filters = (0..params.n_filters)
+            .map(|m| {
+                let (left, center, right) = (edges[m], edges[m + 1], edges[m + 2]);
+                let weights: Vec<f32> = (0..n_bins)
+                    .map(|k| {
+                        let k = k as f32;
+                        if k > left && k <= center {
+                            (k - left) / (center - left)
+                        } else if k > center && k < right {
+                            (right - k) / (right - center)
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect();
+                match weights.iter().position(|w| *w > 0.0) {
+                    Some(first) => {
+                        let last = weights.iter().rposition(|w| *w > 0.0).unwrap_or(first);
+                        (first, weights[first..=last].to_vec())
+                    }
+                    // narrower than a bin, use the closest bin
+                    None => (usize::min(center.round() as usize, n_bins - 1), vec![1.0]),
+                }
+            })
+            .collect();
+        let skip = usize::min(params.ceps_skip, params.n_filters);
+        let n_ceps = usize::min(params.n_ceps, params.n_filters - skip);
+        let lifter = (skip..skip + n_ceps)
+            .map(|n| {
+                if params.lifter > 0 {
+                    let l = params.lifter as f32;
+                    1.0 + l / 2.0 * f32::sin(std::f32::consts::PI * n as f32 / l)
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+        Filterbank {
+            filters,
+            lifter,
+            skip,
+            dct: DCTplanner::new().plan_dct2(params.n_filters),
+            dct_input: vec![0.0; params.n_filters],
+            dct_output: vec![0.0; params.n_filters],
+        }
+    }
+
+    /**
+     * Number of cepstral coefficients per frame
+     */
+    pub fn n_ceps(&self) -> usize {
+        self.lifter.len()
+    }
+
+    /**
+     * Number of filters
+     */
+    pub fn n_filters(&self) -> usize {
+        self.filters.len()
+    }
+
+    /**
+     * Energy of each filter
+     */
+    pub fn apply(&self, magnitude: &[f32]) -> Vec<f32> {
+        (0..self.filters.len())
+            .map(|filter| self.energy(filter, magnitude))
+            .collect()
+    }
+
+    /**
+     * Energy of one filter
+     */
+    fn energy(&self, filter: usize, magnitude: &[f32]) -> f32 {
+        let (first, weights) = &self.filters[filter];
+        weights
+            .iter()
+            .zip(&magnitude[*first..])
+            .map(|(w, m)| w * m)
+            .sum()
+    }
+
+    /**
+     * Liftered cepstral coefficients of a magnitude spectrum
+     */
+    pub fn cepstrum(&mut self, magnitude: &[f32]) -> Vec<f32> {
+        for filter in 0..self.filters.len() {
+            self.dct_input[filter] = f32::ln(self.energy(filter, magnitude) + 1e-6);
+        }
+        self.dct
+            .process_dct2(&mut self.dct_input, &mut self.dct_output);
+        self.dct_output
+            .iter()
+            .skip(self.skip)
+            .zip(self.lifter.iter())
+            .map(|(c, l)| c * l)
+            .collect()
+    }
+}
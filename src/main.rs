@@ -7,116 +7,526 @@ extern crate rayon;
 
 use rand::{thread_rng, Rng};
 use rayon::prelude::*;
+use std::collections::HashMap;
 use std::env;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
 pub mod alignments;
 pub mod audio;
+pub mod benchmark;
+pub mod cache;
 pub mod clustering;
+pub mod decoding;
+pub mod detection;
 pub mod discovery;
 pub mod error;
+pub mod evaluation;
+pub mod features;
+pub mod filterbank;
 pub mod neural;
 pub mod numerics;
 pub mod reporting;
 pub mod spectrogram;
 
+/// Files that could not be processed and why
+type Failures = Vec<(String, error::DiscoveryError)>;
+
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> error::Result<()> {
     println!("==== Pattern Discovery ====");
     println!("# by Daniel Kohlsdorf     #");
     println!("===========================");
 
-    let templates = reporting::Templates::from_toml("project/config/Templates.toml".to_string());
-    let discover = discovery::Discovery::from_toml("project/config/Discovery.toml".to_string());
+    let templates = reporting::Templates::from_toml("project/config/Templates.toml".to_string())?;
+    let discover = discovery::Discovery::from_toml("project/config/Discovery.toml".to_string())?;
     println!("Template Config:  {:?}", templates);
     println!("Discovery Config: {:?}", discover);
 
     let args: Vec<String> = env::args().collect();
     let folder = &args[1];
+    if folder == "bench" {
+        let seconds = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(600);
+        benchmark::spectrogram(&discover, seconds);
+        return Ok(());
+    }
+    if folder == "eval" {
+        let folder = args.get(2).map(String::as_str).unwrap_or(".");
+        return evaluate(folder, &templates, &discover);
+    }
 
     println!("Args: {:?}", args);
-    dump_interesting(folder, &templates.out_audio, &discover);
-    auto_encoder(&templates.out_audio, &templates, &discover);
-    learn(&templates.out_audio, &templates, &discover);
+    let failed = dump_interesting(folder, &templates, &discover)?;
+    if let spectrogram::ClusterInput::Features = discover.cluster_input {
+        auto_encoder(&templates.out_audio, &templates, &discover)?;
+    }
+    learn(&templates.out_audio, &templates, &discover, failed)
 }
 
-fn all_files(folder: &str) -> Vec<String> {
+/**
+ * All audio files below a folder, without the cluster and prototype
+ * files written to the output folder
+ */
+fn all_files(folder: &str, extensions: &[String]) -> Vec<String> {
     let mut audio_files: Vec<String> = vec![];
-    for entry in glob::glob(&format!("{}/**/*.wav", folder)).unwrap() {
-        match entry {
-            Ok(path) => {
-                if !path.to_string_lossy().contains("cluster") {
-                    println!("File: {}", path.to_string_lossy());
-                    audio_files.push(String::from(path.to_string_lossy().clone()));
+    let options = glob::MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+    for extension in extensions {
+        let entries = match glob::glob_with(&format!("{}/**/*.{}", folder, extension), options) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("{:?}", e);
+                continue;
+            }
+        };
+        for entry in entries {
+            match entry {
+                Ok(path) => {
+                    let name = path.to_string_lossy();
+                    if !name.contains("cluster") && !name.contains("prototype_") {
+                        println!("File: {}", path.to_string_lossy());
+                        audio_files.push(String::from(path.to_string_lossy().clone()));
+                    }
                 }
+                Err(e) => println!("{:?}", e),
             }
-            Err(e) => println!("{:?}", e),
         }
     }
     audio_files
 }
 
-fn dump_interesting(folder: &str, out: &str, discover: &discovery::Discovery) {
-    for (i, file) in all_files(folder).iter().enumerate() {
+/**
+ * Read all slices in parallel, slices that can not be read are skipped
+ */
+fn read_slices(audio_files: &[String]) -> (Vec<(String, audio::AudioData)>, Failures) {
+    let results: Vec<error::Result<audio::AudioData>> = audio_files
+        .par_iter()
+        .enumerate()
+        .map(|(i, file)| audio::AudioData::from_file(file, i))
+        .collect();
+    let mut raw = vec![];
+    let mut failed = vec![];
+    for (file, result) in audio_files.iter().zip(results) {
+        match result {
+            Ok(mut audio) => {
+                audio.id = raw.len();
+                raw.push((file.clone(), audio));
+            }
+            Err(e) => {
+                println!("\t..skip {}: {}", file, e);
+                failed.push((file.clone(), e));
+            }
+        }
+    }
+    (raw, failed)
+}
+
+fn dump_interesting(
+    folder: &str,
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<Failures> {
+    let mut provenance = vec![];
+    let mut profiles = vec![];
+    let mut failed = vec![];
+    for (i, file) in all_files(folder, &discover.extensions).iter().enumerate() {
         println!("Dumping Intersting Slices For {}", file);
-        let raw = audio::AudioData::from_file(&file, i);
-        println!("\t..spectrogram");
-        let spectrogram = spectrogram::NDSequence::new(
-            discover.dft_win,
-            discover.dft_step,
-            discover.ceps_filter,
-            &raw,
-        );
-        println!("\t..detect");
-        let interesting = spectrogram.interesting_ranges(
-            discover.vat_moving,
-            discover.vat_percentile,
-            discover.vat_min_len,
-        );
+        let (n_slices, n_profiles) = (provenance.len(), profiles.len());
+        if let Err(e) = dump_file(file, i, templates, discover, &mut provenance, &mut profiles) {
+            println!("\t..skip {}: {}", file, e);
+            for (slice, _) in provenance.drain(n_slices..) {
+                let _ = std::fs::remove_file(format!("{}/{}", templates.out_audio, slice));
+            }
+            profiles.truncate(n_profiles);
+            failed.push((file.clone(), e));
+        }
+    }
+    templates.write_provenance(&provenance)?;
+    templates.write_noise_profiles(&profiles)?;
+    let detections: Vec<reporting::Annotation> = provenance
+        .into_iter()
+        .map(|(slice, source)| (slice, source, None))
+        .collect();
+    let (min_freq, max_freq) = discover
+        .detection()
+        .band(&discover.features(), discover.sample_rate);
+    templates.write_annotations(&detections, min_freq, max_freq)?;
+    Ok(failed)
+}
 
-        for slice in interesting {
-            let slice_name = format!(
-                "{}/{}_{}_{}.wav",
-                out,
-                i,
-                slice.start * discover.dft_step,
-                slice.stop * discover.dft_step
-            );
+fn dump_file(
+    file: &str,
+    i: usize,
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+    provenance: &mut Vec<(String, audio::Provenance)>,
+    profiles: &mut Vec<(audio::Provenance, Vec<f32>)>,
+) -> error::Result<()> {
+    let streams = audio::AudioStream::open(file, i, discover.channels, Some(discover.sample_rate))?;
+    for mut stream in streams {
+        let channel = audio::channel_name(stream.channel);
+        let mut noise = if discover.noise_reduction {
+            Some(spectrogram::NoiseSampler::new(spectrogram::NOISE_FRAMES))
+        } else {
+            None
+        };
+        let ranges = detect(
+            &mut stream,
+            &format!("{}_{}", i, channel),
+            templates,
+            discover,
+            noise.as_mut(),
+        )?;
+        let interesting: Vec<(usize, usize)> = ranges
+            .iter()
+            .map(|(start, stop)| (start * discover.dft_step, stop * discover.dft_step))
+            .collect();
+        let sample_rate = stream.spec.sample_rate;
+
+        if let Some(noise) = noise {
+            println!("\t..noise profile");
+            match noise.profile(discover.noise_percentile) {
+                Ok(profile) => profiles.push((
+                    audio::Provenance {
+                        source: file.to_string(),
+                        channel: stream.channel,
+                        start: 0,
+                        stop: 0,
+                        sample_rate,
+                    },
+                    profile,
+                )),
+                Err(error::DiscoveryError::EmptyData(msg)) => println!("\t..{}", msg),
+                Err(e) => return Err(e),
+            }
+        }
+
+        stream.rewind()?;
+        stream.slices(&interesting, |start, stop, raw_slice| {
+            let slice_name = format!("{}_{}_{}_{}.wav", i, channel, start, stop);
             println!("\t..dump {}", slice_name);
-            let raw_slice = raw.slice(
-                slice.start * discover.dft_step,
-                slice.stop * discover.dft_step,
-            );
-            raw_slice.write(slice_name);
+            provenance.push((
+                slice_name.clone(),
+                audio::Provenance {
+                    source: file.to_string(),
+                    channel: raw_slice.channel,
+                    start,
+                    stop,
+                    sample_rate,
+                },
+            ));
+            raw_slice.write(format!("{}/{}", templates.out_audio, slice_name))
+        })?;
+    }
+    Ok(())
+}
+
+/**
+ * Detected segments of one stream in frames,
+ * the thresholds are saved under the given name
+ */
+fn detect(
+    stream: &mut audio::AudioStream,
+    name: &str,
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+    noise: Option<&mut spectrogram::NoiseSampler>,
+) -> error::Result<Vec<(usize, usize)>> {
+    let features = discover.features();
+    let detection = discover.detection();
+    println!(
+        "\t..spectrogram channel {}",
+        audio::channel_name(stream.channel)
+    );
+    let mut preprocessor =
+        audio::Preprocessor::new(&discover.preprocessing(), stream.spec.sample_rate);
+    let mut detector = detection.detector(&features, stream.spec.sample_rate);
+    let activations = spectrogram::streamed_activations(
+        stream,
+        &mut preprocessor,
+        detector.as_mut(),
+        &features,
+        noise,
+    )?;
+    println!("\t..detect");
+    let thresholds = detection::Thresholds::new(&activations, &detection)?;
+    templates.write_thresholds(name, &thresholds, &detection)?;
+    Ok(detection::segments(
+        &thresholds.ranges(0),
+        &discover.segments(),
+        detection.min_len,
+        activations.len(),
+    ))
+}
+
+/**
+ * Run the detector on every recording with reference annotations
+ * and compare the detections to the annotated events
+ */
+fn evaluate(
+    folder: &str,
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<()> {
+    std::fs::create_dir_all(&templates.out_docs)?;
+    let mut evaluations = vec![];
+    for (i, file) in all_files(folder, &discover.extensions).iter().enumerate() {
+        let reference = match evaluation::reference_file(file) {
+            Some(reference) => reference,
+            None => {
+                println!("\t..no reference annotations for {}", file);
+                continue;
+            }
+        };
+        println!("Evaluating {} against {}", file, reference);
+        let result = evaluation::read_events(&reference).and_then(|references| {
+            let mut detections = vec![];
+            let streams =
+                audio::AudioStream::open(file, i, discover.channels, Some(discover.sample_rate))?;
+            for mut stream in streams {
+                let name = format!("{}_{}", i, audio::channel_name(stream.channel));
+                let frame = discover.dft_step as f32 / stream.spec.sample_rate as f32;
+                detections.extend(
+                    detect(&mut stream, &name, templates, discover, None)?
+                        .iter()
+                        .map(|(start, stop)| evaluation::Event {
+                            start: *start as f32 * frame,
+                            stop: *stop as f32 * frame,
+                            channel: stream.channel,
+                        }),
+                );
+            }
+            // without channels in the reference a call detected on several channels counts once
+            if references.iter().all(|r| r.channel.is_none()) {
+                detections = evaluation::merge_channels(&detections);
+            }
+            Ok(evaluation::Evaluation::new(
+                &detections,
+                &references,
+                discover.eval_iou,
+            ))
+        });
+        match result {
+            Ok(result) => {
+                let (start_error, stop_error) = result.boundary_error();
+                println!(
+                    "\t..precision {:.3} recall {:.3} f1 {:.3} boundary error {:.3}s {:.3}s",
+                    result.precision(),
+                    result.recall(),
+                    result.f1(),
+                    start_error,
+                    stop_error
+                );
+                evaluations.push((file.clone(), result));
+            }
+            Err(e) => println!("\t..skip {}: {}", file, e),
         }
     }
+    let mut overall = evaluation::Evaluation::default();
+    for (_, result) in evaluations.iter() {
+        overall.add(result);
+    }
+    let (start_error, stop_error) = overall.boundary_error();
+    println!(
+        "Overall: {} references {} detections {} matches precision {:.3} recall {:.3} f1 {:.3} boundary error {:.3}s {:.3}s",
+        overall.references,
+        overall.detections,
+        overall.matches,
+        overall.precision(),
+        overall.recall(),
+        overall.f1(),
+        start_error,
+        stop_error
+    );
+    templates.write_evaluation(&evaluations, &overall)
 }
 
-fn auto_encoder(folder: &str, templates: &reporting::Templates, discover: &discovery::Discovery) {
-    let audio_files: Vec<String> = all_files(folder);
-    println!("==== Extract Interesting Regions ==== ");
-    let raw: Vec<audio::AudioData> = audio_files
+/**
+ * File name of a slice as stored in the provenance
+ */
+fn slice_name(file: &str) -> String {
+    Path::new(file)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/**
+ * Noise profile of the recording each slice was cut from,
+ * none for all slices without noise reduction
+ */
+fn noise_profiles(
+    slice_files: &[String],
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<Vec<Option<Vec<f32>>>> {
+    if !discover.noise_reduction {
+        return Ok(vec![None; slice_files.len()]);
+    }
+    let provenance = templates.read_provenance()?;
+    let profiles = templates.read_noise_profiles()?;
+    Ok(slice_files
+        .iter()
+        .map(|file| {
+            provenance.get(&slice_name(file)).and_then(|source| {
+                profiles
+                    .get(&(source.source.clone(), source.channel_name()))
+                    .cloned()
+            })
+        })
+        .collect())
+}
+
+/**
+ * Features of each slice, loaded from the feature cache
+ * if the slice and the parameters did not change since they were computed
+ */
+fn sequences(
+    slice_files: &[String],
+    raw: &[audio::AudioData],
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<Vec<spectrogram::NDSequence>> {
+    let cache = cache::FeatureCache::new(&templates.out_cache)?;
+    let profiles = noise_profiles(slice_files, templates, discover)?;
+    let noise = discover.noise_reduction();
+    let features = discover.features();
+    let preprocessing = discover.preprocessing();
+    let cached = AtomicUsize::new(0);
+    let sequences = raw
         .par_iter()
-        .enumerate()
-        .map(|(i, file)| audio::AudioData::from_file(&file, i))
-        .collect();
-    println!("Extracting Spectrograms");
-    let signals: Vec<spectrogram::NDSequence> = raw
+        .zip(slice_files.par_iter().zip(profiles.par_iter()))
+        .map(|(raw, (file, profile))| {
+            let params = format!(
+                "{} {:?} {:?} {:?} {:?}",
+                raw.spec.sample_rate, features, preprocessing, noise, profile
+            );
+            let key = cache.key(file, &raw.data, &params);
+            if let Some(mut sequence) = cache.load(key) {
+                cached.fetch_add(1, Ordering::Relaxed);
+                sequence.audio_id = raw.id;
+                return Ok(sequence);
+            }
+            let sequence = spectrogram::NDSequence::new(
+                &features,
+                &raw.preprocess(&preprocessing),
+                noise.as_ref().zip(profile.as_deref()),
+            );
+            cache.store(key, &sequence)?;
+            Ok(sequence)
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    println!(
+        "Features of {} slices, {} from cache",
+        sequences.len(),
+        cached.into_inner()
+    );
+    Ok(sequences)
+}
+
+/**
+ * Frequency contour of each slice. Contours are computed on every run,
+ * they are not stored in the feature cache.
+ */
+fn contours(
+    slice_files: &[String],
+    raw: &[audio::AudioData],
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<Vec<spectrogram::NDSequence>> {
+    let profiles = noise_profiles(slice_files, templates, discover)?;
+    let noise = discover.noise_reduction();
+    let features = discover.features();
+    let contour = discover.contour();
+    let preprocessing = discover.preprocessing();
+    let contours: Vec<(spectrogram::NDSequence, Vec<bool>)> = raw
         .par_iter()
-        .map(|raw| {
-            spectrogram::NDSequence::new(
-                discover.dft_win,
-                discover.dft_step,
-                discover.ceps_filter,
-                raw,
+        .zip(profiles.par_iter())
+        .map(|(raw, profile)| {
+            spectrogram::NDSequence::contour(
+                &features,
+                &contour,
+                &raw.preprocess(&preprocessing),
+                noise.as_ref().zip(profile.as_deref()),
             )
         })
         .collect();
+    for (file, (sequence, voiced)) in slice_files.iter().zip(contours.iter()) {
+        println!(
+            "\t..contour {}: {} of {} frames voiced",
+            slice_name(file),
+            voiced.iter().filter(|v| **v).count(),
+            sequence.len()
+        );
+    }
+    Ok(contours.into_iter().map(|(sequence, _)| sequence).collect())
+}
+
+/**
+ * Resynthesize the magnitude spectrogram of the medoid of each cluster
+ */
+fn write_prototypes(
+    grouped: &[Vec<usize>],
+    distances: &[f32],
+    slice_files: &[String],
+    raw: &[audio::AudioData],
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<()> {
+    let profiles = noise_profiles(slice_files, templates, discover)?;
+    let noise = discover.noise_reduction();
+    let features = discover.features();
+    for (i, cluster) in grouped.iter().enumerate() {
+        let n = raw.len();
+        if let Some(medoid) = clustering::AgglomerativeClustering::medoid(cluster, distances, n) {
+            let magnitudes = spectrogram::model_magnitudes(
+                &features,
+                &raw[medoid].preprocess(&discover.preprocessing()),
+                noise.as_ref().zip(profiles[medoid].as_deref()),
+            );
+            templates.write_prototype(
+                i,
+                &magnitudes,
+                &features,
+                &discover.cluster_audio(),
+                raw[medoid].spec.sample_rate,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn auto_encoder(
+    folder: &str,
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+) -> error::Result<()> {
+    let audio_files: Vec<String> = all_files(folder, &[String::from("wav")]);
+    println!("==== Extract Interesting Regions ==== ");
+    let (raw, _) = read_slices(&audio_files);
+    if raw.is_empty() {
+        return Err(error::DiscoveryError::EmptyData(
+            "no interesting regions to learn the auto encoder from".to_string(),
+        ));
+    }
+    let (slice_files, raw): (Vec<String>, Vec<audio::AudioData>) = raw.into_iter().unzip();
+    println!("Extracting Spectrograms");
+    let signals = sequences(&slice_files, &raw, templates, discover)?;
     println!("==== Learn Auto Encoder ==== ");
     let mut nn = neural::AutoEncoder::new(signals[0].n_bins, discover.auto_encoder);
     for epoch in 0..discover.epochs {
         let mut total = 0.0;
         let mut total_err = 0.0;
-        let lr = neural::AutoEncoder::step_decay(epoch as f32, &discover);
+        let lr = neural::AutoEncoder::step_decay(epoch as f32, discover);
         for signal in &signals {
             let mut order: Vec<usize> = (0..signal.len()).collect();
             let slice: &mut [usize] = &mut order;
@@ -133,40 +543,56 @@ fn auto_encoder(folder: &str, templates: &reporting::Templates, discover: &disco
         }        
         println!("{} lr: {}", total_err / total, lr);
     }
-    templates.save_encoder(nn).unwrap();
+    templates.save_encoder(nn)?;
     println!("==== Done! ==== ");
+    Ok(())
 }
 
-fn learn(folder: &str, templates: &reporting::Templates, discover: &discovery::Discovery) {
-    let audio_files: Vec<String> = all_files(folder);
-    let nn = templates.read_encoder().unwrap();
+fn learn(
+    folder: &str,
+    templates: &reporting::Templates,
+    discover: &discovery::Discovery,
+    mut failed: Failures,
+) -> error::Result<()> {
+    let audio_files: Vec<String> = all_files(folder, &[String::from("wav")]);
+    let provenance = templates.read_provenance()?;
     println!("==== Extract Interesting Regions ==== ");
-    let raw: Vec<audio::AudioData> = audio_files
-        .par_iter()
-        .enumerate()
-        .map(|(i, file)| audio::AudioData::from_file(&file, i))
-        .collect();
+    let (slices, failed_slices) = read_slices(&audio_files);
+    failed.extend(failed_slices);
+    if slices.is_empty() {
+        return Err(error::DiscoveryError::EmptyData(
+            "no interesting regions to cluster".to_string(),
+        ));
+    }
+    let (slice_files, raw): (Vec<String>, Vec<audio::AudioData>) = slices.into_iter().unzip();
     println!("Extracting Spectrograms");
-    let signals: Vec<spectrogram::NDSequence> = raw
-        .par_iter()
-        .map(|raw| {
-            spectrogram::NDSequence::new(
-                discover.dft_win,
-                discover.dft_step,
-                discover.ceps_filter,
-                raw,
-            )
-            .encoded(&nn)
-        })
-        .collect();
+    let signals: Vec<spectrogram::NDSequence> = match discover.cluster_input {
+        spectrogram::ClusterInput::Features => {
+            let nn = templates.read_encoder()?;
+            sequences(&slice_files, &raw, templates, discover)?
+                .into_iter()
+                .map(|sequence| sequence.encoded(&nn))
+                .collect()
+        }
+        spectrogram::ClusterInput::Spectrogram => {
+            sequences(&slice_files, &raw, templates, discover)?
+                .iter()
+                .map(|sequence| sequence.spectrogram_sequence())
+                .collect()
+        }
+        spectrogram::ClusterInput::Contour => contours(&slice_files, &raw, templates, discover)?,
+    };
 
     println!("==== Plot All Regions ==== ");
     let mut file_names = vec![];
+    let mut file_names_raw = vec![];
     let mut file_names_ceps = vec![];
     for (i, signal) in signals.iter().enumerate() {
         let file_id = format!("spec_{}", i);
+        let file_id_raw = format!("raw_spec_{}", i);
         let file_id_ceps = format!("ceps_{}", i);
         let file_spec = format!("spec_{}.png", i);
+        let file_raw = format!("raw_spec_{}.png", i);
         let file_ceps = format!("ceps_{}.png", i);
         let _ = templates.plot(
             file_spec,
@@ -174,6 +600,12 @@ fn learn(folder: &str, templates: &reporting::Templates, discover: &discovery::D
             signal.len_spec() as u32,
             signal.dft_win as u32,
         );
+        let _ = templates.plot(
+            file_raw,
+            &signal.img_raw_spec(),
+            signal.len_spec() as u32,
+            signal.dft_win as u32,
+        );
         let _ = templates.plot(
             file_ceps,
             &signal.img_ceps(),
@@ -181,6 +613,7 @@ fn learn(folder: &str, templates: &reporting::Templates, discover: &discovery::D
             signal.n_bins as u32,
         );
         file_names_ceps.push(file_id_ceps);
+        file_names_raw.push(file_id_raw);
         file_names.push(file_id);
     }
 
@@ -188,27 +621,85 @@ fn learn(folder: &str, templates: &reporting::Templates, discover: &discovery::D
     let n = signals.len();
     let mut workers = alignments::AlignmentWorkers::new(signals);
     let now = Instant::now();
-    workers.align_all(&discover);
+    workers.align_all(discover);
     println!("Align 8 threads took {}", now.elapsed().as_secs());
 
     let result = workers.result.lock().unwrap();
     let distances: Vec<f32> = result.clone();
+    let offsets = workers.offsets.lock().unwrap();
+    let slice_names: Vec<String> = slice_files.iter().map(|file| slice_name(file)).collect();
+    templates.write_alignments(&slice_names, &distances, &offsets)?;
     let (operations, clusters) = clustering::AgglomerativeClustering::clustering(
         distances,
         n,
         discover.clustering_percentile,
-    );
+    )?;
 
     println!("==== Writing Cluster Audio ==== ");
     let grouped = clustering::AgglomerativeClustering::cluster_sets(&operations, &clusters, n);
-    templates.write_slices_audio(&grouped, &raw, 10000);
+    let labels: Vec<String> = slice_files
+        .iter()
+        .map(|file| {
+            let slice = slice_name(file);
+            provenance
+                .get(&slice)
+                .map(|source| source.label())
+                .unwrap_or(slice)
+        })
+        .collect();
+    templates.write_slices_audio(
+        &grouped,
+        &raw,
+        &labels,
+        &discover.cluster_audio(),
+        discover.sample_rate,
+    )?;
+    let mut cluster_of = HashMap::new();
+    for (cluster, members) in grouped.iter().enumerate() {
+        for member in members {
+            cluster_of.insert(*member, cluster);
+        }
+    }
+    let detections: Vec<reporting::Annotation> = slice_files
+        .iter()
+        .enumerate()
+        .filter_map(|(i, file)| {
+            let slice = slice_name(file);
+            let source = provenance.get(&slice)?.clone();
+            Some((slice, source, cluster_of.get(&i).cloned()))
+        })
+        .collect();
+    let (min_freq, max_freq) = discover
+        .detection()
+        .band(&discover.features(), discover.sample_rate);
+    templates.write_annotations(&detections, min_freq, max_freq)?;
+    let with_prototypes = discover.griffin_lim_iterations > 0;
+    if with_prototypes {
+        println!("==== Writing Cluster Prototypes ==== ");
+        write_prototypes(&grouped, &result, &slice_files, &raw, templates, discover)?;
+    }
     println!("==== Generate Report ==== ");
     let mut clustering_files = vec![];
     for cluster in 0..grouped.len() {
         let filename = format!("cluster_{}.wav", cluster);
         clustering_files.push(filename);
+        if with_prototypes {
+            clustering_files.push(format!("prototype_{}.wav", cluster));
+        }
+    }
+    let mut slice_sources = vec![];
+    for file in slice_files.iter() {
+        let slice = slice_name(file);
+        if let Some(source) = provenance.get(&slice) {
+            slice_sources.push((slice, source.clone()));
+        }
     }
-    let _ = templates.write_html("output/result.html".to_string(), &clustering_files, &[]);
+    let _ = templates.write_html(
+        "output/result.html".to_string(),
+        &clustering_files,
+        &slice_sources,
+        &failed,
+    );
     if let Ok(ceps_tex) = templates.dendrograms(&operations, &clusters, file_names_ceps, "ceps") {
         if let Ok(spec_tex) = templates.dendrograms(&operations, &clusters, file_names, "specs") {
             let mut latex_parts =
@@ -216,9 +707,17 @@ fn learn(folder: &str, templates: &reporting::Templates, discover: &discovery::D
             latex_parts.extend(ceps_tex);
             latex_parts.push("\\chapter{Clusters With Spectrum Visualisation}".to_string());
             latex_parts.extend(spec_tex);
+            if let Ok(raw_tex) =
+                templates.dendrograms(&operations, &clusters, file_names_raw, "raw_specs")
+            {
+                latex_parts
+                    .push("\\chapter{Clusters With Spectrum Before Noise Reduction}".to_string());
+                latex_parts.extend(raw_tex);
+            }
             let _ = templates.generate_doc("results.tex".to_string(), latex_parts);
         }
     }
 
     println!("==== Done! ==== ");
+    Ok(())
 }
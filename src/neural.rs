@@ -31,14 +31,14 @@ impl AutoEncoder {
         let mut fp = File::open(file)?;
         let mut buf: Vec<u8> = vec![];
         let _ = fp.read_to_end(&mut buf)?;
-        let decoded: AutoEncoder = deserialize(&buf).unwrap();
+        let decoded: AutoEncoder = deserialize(&buf)?;
         Ok(decoded)
     }
 
     /// save file
     pub fn save_file(&self, file: &str) -> Result<()> {
         let mut fp = File::create(file)?;
-        let encoded: Vec<u8> = serialize(&self).unwrap();
+        let encoded: Vec<u8> = serialize(&self)?;
         fp.write_all(&encoded)?;
         Ok(())
     }
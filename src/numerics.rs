@@ -6,6 +6,8 @@ extern crate serde_derive;
 
 use rand::Rng;
 
+use crate::error::*;
+
 /**
  * Comput sample mean of a slice
  */
@@ -32,7 +34,7 @@ pub fn std(x: &[f32], mu: f32) -> f32 {
  * Min in slice, ignoring infinite values
  */
 pub fn min(x: &[f32]) -> f32 {
-    let mut min = std::f32::INFINITY;
+    let mut min = f32::INFINITY;
     for frame in x {
         if *frame < min && frame.is_finite() {
             min = *frame;
@@ -45,7 +47,7 @@ pub fn min(x: &[f32]) -> f32 {
  * Max in slice ignoring infinite values
  */
 pub fn max(x: &[f32]) -> f32 {
-    let mut max = std::f32::NEG_INFINITY;
+    let mut max = f32::NEG_INFINITY;
     for frame in x {
         if *frame > max && frame.is_finite() {
             max = *frame;
@@ -54,15 +56,101 @@ pub fn max(x: &[f32]) -> f32 {
     max
 }
 
+/**
+ * Moving average over the k values before each position,
+ * zero for the first k positions
+ */
+pub fn moving_avg(x: &[f32], k: usize) -> Vec<f32> {
+    let mut moving_avg = vec![];
+    for i in 0..x.len() {
+        let avg = if i >= k { mean(&x[i - k..i]) } else { 0.0 };
+        moving_avg.push(avg);
+    }
+    moving_avg
+}
+
+/**
+ * Generalized cosine window `a0 - a1 cos(x) + a2 cos(2x) - a3 cos(3x)`
+ * over one period of length len
+ */
+fn cosine_window(len: usize, a: [f32; 4]) -> Vec<f32> {
+    (0..len)
+        .map(|i| {
+            let x = 2.0 * std::f32::consts::PI * i as f32 / len as f32;
+            a[0] - a[1] * f32::cos(x) + a[2] * f32::cos(2.0 * x) - a[3] * f32::cos(3.0 * x)
+        })
+        .collect()
+}
+
 /**
  * Hamming Window
  */
 pub fn hamming(len: usize) -> Vec<f32> {
-    let mut hamming = Vec::new();
-    for i in 0..len {
-        hamming.push(0.54 + 0.46 * f32::cos((2.0 * std::f32::consts::PI * i as f32) / len as f32));
+    cosine_window(len, [0.54, 0.46, 0.0, 0.0])
+}
+
+/**
+ * Hann Window
+ */
+pub fn hann(len: usize) -> Vec<f32> {
+    cosine_window(len, [0.5, 0.5, 0.0, 0.0])
+}
+
+/**
+ * Four term Blackman-Harris Window
+ */
+pub fn blackman_harris(len: usize) -> Vec<f32> {
+    cosine_window(len, [0.35875, 0.48829, 0.14128, 0.01168])
+}
+
+/**
+ * Kaiser Window, beta trades main lobe width for side lobe level
+ */
+pub fn kaiser(len: usize, beta: f32) -> Vec<f32> {
+    (0..len)
+        .map(|i| kaiser_at(2.0 * i as f32 / len as f32 - 1.0, beta))
+        .collect()
+}
+
+/**
+ * Modified bessel function of the first kind and order zero,
+ * by its power series
+ */
+pub fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= (half / k as f32) * (half / k as f32);
+        sum += term;
+        if term < sum * 1e-9 {
+            break;
+        }
+    }
+    sum
+}
+
+/**
+ * Kaiser window at position x in [-1, 1] relative to the window center
+ */
+pub fn kaiser_at(x: f32, beta: f32) -> f32 {
+    if x.abs() > 1.0 {
+        0.0
+    } else {
+        bessel_i0(beta * f32::sqrt(1.0 - x * x)) / bessel_i0(beta)
+    }
+}
+
+/**
+ * Normalised sinc: sin(pi x) / (pi x)
+ */
+pub fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-8 {
+        1.0
+    } else {
+        let pi_x = std::f32::consts::PI * x;
+        f32::sin(pi_x) / pi_x
     }
-    hamming
 }
 
 /**
@@ -122,36 +210,33 @@ pub fn euclidean(x: &[f32], y: &[f32]) -> f32 {
 /**
  * Extract percentile for example the median is at percentile(x, 0.5)
  */
-pub fn percentile(x: &mut [f32], perc: f32) -> f32 {
-    let n = x.len() as f32 * perc;
+pub fn percentile(x: &mut [f32], perc: f32) -> Result<f32> {
     let mut numbers: Vec<f32> = x
         .iter()
         .filter_map(|x| if x.is_nan() { None } else { Some(*x) })
         .collect();
+    if numbers.is_empty() {
+        return Err(DiscoveryError::EmptyData(
+            "percentile of an empty sequence".to_string(),
+        ));
+    }
     numbers.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    numbers[n as usize]
+    let n = usize::min((numbers.len() as f32 * perc) as usize, numbers.len() - 1);
+    Ok(numbers[n])
 }
 
 /**
  * absolute distance for unsigned
  */
 pub fn abs(n: usize, m: usize) -> usize {
-    if n > m {
-        n - m
-    } else {
-        m - n
-    }
+    n.abs_diff(m)
 }
 
 /**
  *  distance for unsigned bounded by 0
  */
 pub fn diff(n: usize, m: usize) -> usize {
-    if n > m {
-        n - m
-    } else {
-        0
-    }
+    n.saturating_sub(m)
 }
 
 /**
@@ -260,8 +345,8 @@ impl Mat {
     /// multiplication, element by element
     pub fn mul_ebe(&self, other: &Mat) -> Mat {
         let mut flat = self.flat.clone();
-        for i in 0..flat.len() {
-            flat[i] *= other.flat[i];
+        for (x, y) in flat.iter_mut().zip(other.flat.iter()) {
+            *x *= y;
         }
         Mat {
             cols: self.cols,
@@ -272,8 +357,8 @@ impl Mat {
     /// addition, element by element
     pub fn add_ebe(&self, other: &Mat) -> Mat {
         let mut flat = self.flat.clone();
-        for i in 0..flat.len() {
-            flat[i] += other.flat[i];
+        for (x, y) in flat.iter_mut().zip(other.flat.iter()) {
+            *x += y;
         }
         Mat {
             cols: self.cols,
@@ -284,8 +369,8 @@ impl Mat {
     /// addition, element by element
     pub fn sub_ebe(&self, other: &Mat) -> Mat {
         let mut flat = self.flat.clone();
-        for i in 0..flat.len() {
-            flat[i] -= other.flat[i];
+        for (x, y) in flat.iter_mut().zip(other.flat.iter()) {
+            *x -= y;
         }
         Mat {
             cols: self.cols,
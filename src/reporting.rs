@@ -3,13 +3,45 @@ extern crate toml;
 
 use crate::audio::*;
 use crate::clustering::*;
+use crate::detection::{DetectionParams, Thresholds};
 use crate::error::*;
+use crate::evaluation::Evaluation;
+use crate::features::FeatureParams;
 use crate::neural::*;
 use crate::spectrogram::*;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
+
+/**
+ * A detected slice: its name, where it was cut from and its cluster, if known
+ */
+pub type Annotation = (String, Provenance, Option<usize>);
+
+/**
+ * Escape text for the result page
+ */
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/**
+ * How the members of a cluster are joined into one audio file
+ */
+#[derive(Clone, Debug)]
+pub struct ClusterAudioParams {
+    /// silence between members in seconds
+    pub gap: f32,
+    /// fade in and fade out of each member in seconds
+    pub fade: f32,
+    pub normalization: Normalization,
+    /// griffin lim iterations of the prototype audio, 0 writes no prototypes
+    pub griffin_lim_iterations: usize,
+}
 
 #[derive(Deserialize, Debug)]
 pub struct Templates {
@@ -18,6 +50,8 @@ pub struct Templates {
     pub out_docs: String,
     pub out_images: String,
     pub out_audio: String,
+    pub out_cache: String,
+    pub out_annotations: String,
     pub document: String,
     pub dendrogram: String,
     pub figure: String,
@@ -68,27 +102,260 @@ impl Templates {
         Ok(())
     }
 
+    /// save where each slice was cut from
+    pub fn write_provenance(&self, slices: &[(String, Provenance)]) -> Result<()> {
+        let mut fp = File::create(format!("{}/segments.tsv", self.out_audio))?;
+        fp.write_fmt(format_args!(
+            "slice\tsource\tchannel\tstart\tstop\tsample_rate\n"
+        ))?;
+        for (slice, provenance) in slices {
+            fp.write_fmt(format_args!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                slice,
+                provenance.source,
+                provenance.channel_name(),
+                provenance.start,
+                provenance.stop,
+                provenance.sample_rate
+            ))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Save the distance and the best frequency shift in Hz of every
+     * pair of slices, the shift is applied to the second slice
+     */
+    pub fn write_alignments(
+        &self,
+        slices: &[String],
+        distances: &[f32],
+        offsets: &[f32],
+    ) -> Result<()> {
+        let n = slices.len();
+        let mut fp = File::create(format!("{}/alignments.tsv", self.out_docs))?;
+        fp.write_fmt(format_args!("slice\tother\tdistance\tshift_hz\n"))?;
+        for i in 0..n {
+            for j in 0..n {
+                if i != j {
+                    fp.write_fmt(format_args!(
+                        "{}\t{}\t{}\t{}\n",
+                        slices[i],
+                        slices[j],
+                        distances[i * n + j],
+                        offsets[i * n + j]
+                    ))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Save the smoothed activation and the thresholds of each frame of a recording
+     */
+    pub fn write_thresholds(
+        &self,
+        name: &str,
+        thresholds: &Thresholds,
+        params: &DetectionParams,
+    ) -> Result<()> {
+        let mut fp = File::create(format!("{}/thresholds_{}.tsv", self.out_docs, name))?;
+        fp.write_fmt(format_args!("frame\tseconds\tactivation\ton\toff\n"))?;
+        for (t, activation) in thresholds.smoothed.iter().enumerate() {
+            fp.write_fmt(format_args!(
+                "{}\t{:.3}\t{}\t{}\t{}\n",
+                t,
+                t as f32 / params.frame_rate,
+                activation,
+                thresholds.on[t],
+                thresholds.off[t]
+            ))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Save precision, recall, f1 and mean boundary errors of the
+     * detections in each evaluated recording and over all of them
+     */
+    pub fn write_evaluation(
+        &self,
+        evaluations: &[(String, Evaluation)],
+        overall: &Evaluation,
+    ) -> Result<()> {
+        let mut fp = File::create(format!("{}/evaluation.tsv", self.out_docs))?;
+        fp.write_fmt(format_args!(
+            "file\treferences\tdetections\tmatches\tprecision\trecall\tf1\tstart_error\tstop_error\n"
+        ))?;
+        let overall = ("overall".to_string(), overall.clone());
+        for (file, result) in evaluations.iter().chain(std::iter::once(&overall)) {
+            let (start_error, stop_error) = result.boundary_error();
+            fp.write_fmt(format_args!(
+                "{}\t{}\t{}\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\n",
+                file,
+                result.references,
+                result.detections,
+                result.matches,
+                result.precision(),
+                result.recall(),
+                result.f1(),
+                start_error,
+                stop_error
+            ))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Save a Raven selection table and an Audacity label track for each
+     * source recording, with the cluster of each slice if it is known.
+     * Files are named by the number of the recording, as the slices,
+     * and the name of the recording.
+     */
+    pub fn write_annotations(
+        &self,
+        slices: &[Annotation],
+        min_freq: f32,
+        max_freq: f32,
+    ) -> Result<()> {
+        std::fs::create_dir_all(&self.out_annotations)?;
+        let mut recordings: BTreeMap<String, Vec<&Annotation>> = BTreeMap::new();
+        for slice in slices {
+            let number = slice.0.split('_').next().unwrap_or_default();
+            let stem = Path::new(&slice.1.source)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            recordings
+                .entry(format!("{}_{}", number, stem))
+                .or_default()
+                .push(slice);
+        }
+        let clustered = slices.iter().any(|(_, _, cluster)| cluster.is_some());
+        for (name, mut slices) in recordings {
+            slices.sort_by_key(|(_, provenance, _)| (provenance.channel, provenance.start));
+            let mut raven =
+                File::create(format!("{}/{}.selections.txt", self.out_annotations, name))?;
+            let mut audacity =
+                File::create(format!("{}/{}.labels.txt", self.out_annotations, name))?;
+            raven.write_fmt(format_args!(
+                "Selection\tView\tChannel\tBegin Time (s)\tEnd Time (s)\tLow Freq (Hz)\tHigh Freq (Hz)\tSlice{}\n",
+                if clustered { "\tCluster" } else { "" }
+            ))?;
+            for (i, (slice, provenance, cluster)) in slices.iter().enumerate() {
+                let high = f32::min(max_freq, provenance.sample_rate as f32 / 2.0);
+                let cluster = cluster.map(|c| c.to_string()).unwrap_or_default();
+                raven.write_fmt(format_args!(
+                    "{}\tSpectrogram 1\t{}\t{:.6}\t{:.6}\t{:.1}\t{:.1}\t{}{}\n",
+                    i + 1,
+                    provenance.channel.unwrap_or(0) + 1,
+                    provenance.start_seconds(),
+                    provenance.stop_seconds(),
+                    min_freq,
+                    high,
+                    slice,
+                    if clustered {
+                        format!("\t{}", cluster)
+                    } else {
+                        String::new()
+                    }
+                ))?;
+                let label = if cluster.is_empty() {
+                    slice.to_string()
+                } else {
+                    format!("cluster {} {}", cluster, slice)
+                };
+                audacity.write_fmt(format_args!(
+                    "{:.6}\t{:.6}\t{}\n\\\t{:.1}\t{:.1}\n",
+                    provenance.start_seconds(),
+                    provenance.stop_seconds(),
+                    label,
+                    min_freq,
+                    high
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// load where each slice was cut from, by slice file name
+    pub fn read_provenance(&self) -> Result<HashMap<String, Provenance>> {
+        let mut file = File::open(format!("{}/segments.tsv", self.out_audio))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let mut slices = HashMap::new();
+        for line in content.lines().skip(1) {
+            let cols: Vec<&str> = line.split('\t').collect();
+            if cols.len() == 6 {
+                let provenance = Provenance {
+                    source: cols[1].to_string(),
+                    channel: cols[2].parse().ok(),
+                    start: cols[3].parse().unwrap_or(0),
+                    stop: cols[4].parse().unwrap_or(0),
+                    sample_rate: cols[5].parse().unwrap_or(1),
+                };
+                slices.insert(cols[0].to_string(), provenance);
+            }
+        }
+        Ok(slices)
+    }
+
+    /// save the noise profile of each recording and channel
+    pub fn write_noise_profiles(&self, profiles: &[(Provenance, Vec<f32>)]) -> Result<()> {
+        let mut fp = File::create(format!("{}/noise_profiles.tsv", self.out_audio))?;
+        fp.write_fmt(format_args!("source\tchannel\tprofile\n"))?;
+        for (recording, profile) in profiles {
+            let profile: Vec<String> = profile.iter().map(|x| x.to_string()).collect();
+            fp.write_fmt(format_args!(
+                "{}\t{}\t{}\n",
+                recording.source,
+                recording.channel_name(),
+                profile.join(" ")
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// load the noise profiles by source file and channel name
+    pub fn read_noise_profiles(&self) -> Result<HashMap<(String, String), Vec<f32>>> {
+        let mut file = File::open(format!("{}/noise_profiles.tsv", self.out_audio))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let mut profiles = HashMap::new();
+        for line in content.lines().skip(1) {
+            let cols: Vec<&str> = line.split('\t').collect();
+            if cols.len() == 3 {
+                let profile = cols[2]
+                    .split(' ')
+                    .map(|x| x.parse().unwrap_or(0.0))
+                    .collect();
+                profiles.insert((cols[0].to_string(), cols[1].to_string()), profile);
+            }
+        }
+        Ok(profiles)
+    }
+
     /// load from config
-    pub fn from_toml(file: String) -> Templates {
+    pub fn from_toml(file: String) -> Result<Templates> {
         let mut template_conf = String::new();
-        let _ = File::open(file)
-            .expect("Template file not found")
-            .read_to_string(&mut template_conf);
-        let templates: Templates = toml::from_str(&template_conf).unwrap();
-        templates
+        File::open(file)?.read_to_string(&mut template_conf)?;
+        let templates: Templates = toml::from_str(&template_conf)?;
+        Ok(templates)
     }
 
     pub fn write_html(
         &self,
         out: String,
         cluster_files: &[String],
-        sub_sequence: &[String],
+        sub_sequence: &[(String, Provenance)],
+        failed: &[(String, DiscoveryError)],
     ) -> Result<()> {
         let mut clusters = String::new();
         let mut sequences = String::new();
         clusters.push_str("<ul>");
         for cluster in cluster_files.iter() {
-            let p = format!("{}/{}", "audio", cluster);
+            let p = escape_html(&format!("{}/{}", "audio", cluster));
             clusters.push_str(&format!(
                 "<li><a href=\"{}\" download={}>{}</a></li>\n",
                 &p, &p, &p
@@ -96,20 +363,37 @@ impl Templates {
         }
         clusters.push_str("</ul>");
         sequences.push_str("<ul>");
-        for cluster in sub_sequence.iter() {
-            let p = format!("{}/{}", "audio", cluster);
+        for (slice, provenance) in sub_sequence.iter() {
+            let p = escape_html(&format!("{}/{}", "audio", slice));
             sequences.push_str(&format!(
-                "<li><a href=\"{}\" download={}>{}</a></li>\n",
-                &p, &p, &p
+                "<li><a href=\"{}\" download={}>{}</a> {} channel {} [{:.3}s - {:.3}s]</li>\n",
+                &p,
+                &p,
+                &p,
+                escape_html(&provenance.source),
+                provenance.channel_name(),
+                provenance.start_seconds(),
+                provenance.stop_seconds()
             ));
         }
         sequences.push_str("</ul>");
+        let mut failures = String::new();
+        failures.push_str("<ul>");
+        for (file, error) in failed.iter() {
+            failures.push_str(&format!(
+                "<li>{}: {}</li>\n",
+                escape_html(file),
+                escape_html(&error.to_string())
+            ));
+        }
+        failures.push_str("</ul>");
         let mut file = File::open(&self.result_html)?;
         let mut template = String::new();
         file.read_to_string(&mut template)?;
         let filled = template
             .replace("[CLUSTERS_WAV]", &clusters)
-            .replace("[SUB_WAV]", &sequences);
+            .replace("[SUB_WAV]", &sequences)
+            .replace("[FAILED]", &failures);
         let mut output = File::create(out)?;
         output.write_fmt(format_args!("{}", filled))?;
 
@@ -203,7 +487,7 @@ impl Templates {
     pub fn plot(&self, file: String, pixels: &[u8], rows: u32, cols: u32) -> Result<()> {
         let output = File::create(format!("{}/{}", self.out_images, file))?;
         let encoder = image::png::PNGEncoder::new(output);
-        encoder.encode(&pixels, cols, rows, image::ColorType::Gray(8))?;
+        encoder.encode(pixels, cols, rows, image::ColorType::Gray(8))?;
         Ok(())
     }
 
@@ -238,27 +522,75 @@ impl Templates {
         Ok(tree_latex.replace("<caption>", caption))
     }
 
-    // output audio
+    // output audio, all members are resampled to the same rate,
+    // faded, normalized and separated by silence.
+    // Each member is marked with its label.
     pub fn write_slices_audio(
         &self,
         clustering: &[Vec<usize>],
         audio: &[AudioData],
-        n_gaps: usize,
-    ) {
+        labels: &[String],
+        params: &ClusterAudioParams,
+        sample_rate: u32,
+    ) -> Result<()> {
+        let n_gaps = (params.gap * sample_rate as f32) as usize;
+        let n_fade = (params.fade * sample_rate as f32) as usize;
         for (i, cluster) in clustering.iter().enumerate() {
             if !cluster.is_empty() {
                 let filename = format!("{}/cluster_{}.wav", self.out_audio, i);
-                let spec = audio[cluster[0]].spec;
+                let mut spec = audio[cluster[0]].spec;
+                spec.sample_rate = sample_rate;
                 let mut output = AudioData {
                     id: 0,
                     spec,
+                    channel: None,
                     data: vec![],
                 };
+                let mut markers = vec![];
                 for audio_id in cluster {
-                    output.append(n_gaps, &mut audio[*audio_id].clone());
+                    let mut member = audio[*audio_id].resample(sample_rate);
+                    member.normalize(params.normalization);
+                    member.fade(n_fade);
+                    markers.push(CueMarker {
+                        start: output.data.len() + n_gaps,
+                        len: member.data.len(),
+                        label: labels[*audio_id].clone(),
+                    });
+                    output.append(n_gaps, &mut member);
                 }
-                output.write(filename);
+                output.write_with_markers(filename, &markers)?;
             }
         }
+        Ok(())
+    }
+
+    /**
+     * Resynthesize the magnitude spectrogram of the prototype of a cluster
+     * by griffin lim and save it next to the audio of the cluster
+     */
+    pub fn write_prototype(
+        &self,
+        cluster: usize,
+        magnitudes: &[Vec<f32>],
+        features: &FeatureParams,
+        params: &ClusterAudioParams,
+        sample_rate: u32,
+    ) -> Result<()> {
+        let griffin_lim = GriffinLim::new(features.fft_size, features.fft_step, features.window);
+        let mut prototype = AudioData {
+            id: 0,
+            spec: hound::WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            },
+            channel: None,
+            data: griffin_lim.reconstruct(magnitudes, params.griffin_lim_iterations),
+        };
+        // fade first, the edges of the reconstruction are the least consistent
+        prototype.fade((params.fade * sample_rate as f32) as usize);
+        prototype.normalize(params.normalization);
+        prototype.write(format!("{}/prototype_{}.wav", self.out_audio, cluster))
     }
 }
@@ -1,96 +1,811 @@
-use rustdct::DCTplanner;
+use bincode::{deserialize, serialize};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
 use rustfft::num_complex::Complex;
 use rustfft::num_traits::Zero;
-use rustfft::FFTplanner;
+use rustfft::{FFTplanner, FFT};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
 
 use crate::audio::*;
+use crate::detection::*;
+use crate::error::*;
+use crate::features::*;
 use crate::neural::*;
 use crate::numerics::*;
 
+/**
+ * Fft of real input of even size, computed by a complex fft of half the size
+ * over the even samples as real and the odd samples as imaginary part.
+ */
+pub struct RealFFT {
+    size: usize,
+    fft: Arc<dyn FFT<f32>>,
+    /// `exp(-2 pi i k / size)` for the first half of the bins
+    twiddles: Vec<Complex<f32>>,
+}
+
+/**
+ * Buffers of one thread running a real fft
+ */
+pub struct FFTScratch {
+    input: Vec<Complex<f32>>,
+    output: Vec<Complex<f32>>,
+}
+
+impl RealFFT {
+    pub fn new(size: usize) -> RealFFT {
+        assert!(size.is_multiple_of(2), "real fft of odd size {}", size);
+        let half = size / 2;
+        let twiddles = (0..half)
+            .map(|k| {
+                let angle = -2.0 * std::f32::consts::PI * k as f32 / size as f32;
+                Complex::new(f32::cos(angle), f32::sin(angle))
+            })
+            .collect();
+        RealFFT {
+            size,
+            fft: FFTplanner::new(false).plan_fft(half),
+            twiddles,
+        }
+    }
+
+    pub fn scratch(&self) -> FFTScratch {
+        FFTScratch {
+            input: vec![Complex::zero(); self.size / 2],
+            output: vec![Complex::zero(); self.size / 2],
+        }
+    }
+
+    /**
+     * Magnitude of the first `size / 2` bins of the fft
+     * of the input multiplied by the window
+     */
+    pub fn magnitude(&self, input: &[f32], window: &[f32], scratch: &mut FFTScratch) -> Vec<f32> {
+        let half = self.size / 2;
+        for (n, z) in scratch.input.iter_mut().enumerate() {
+            *z = Complex::new(
+                input[2 * n] * window[2 * n],
+                input[2 * n + 1] * window[2 * n + 1],
+            );
+        }
+        self.fft.process(&mut scratch.input, &mut scratch.output);
+        let z = &scratch.output;
+        let mut magnitude = Vec::with_capacity(half);
+        magnitude.push(f32::abs(z[0].re + z[0].im));
+        for k in 1..half {
+            let (a, b) = (z[k], z[half - k]);
+            // even and odd samples' spectra: (a + b*) / 2 and (a - b*) / 2i
+            let even = Complex::new(a.re + b.re, a.im - b.im);
+            let odd = Complex::new(a.im + b.im, b.re - a.re);
+            let x = even + self.twiddles[k] * odd;
+            magnitude.push(0.5 * f32::sqrt(x.norm_sqr()));
+        }
+        magnitude
+    }
+}
+
+/**
+ * Window function applied to each frame before the fft
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Window {
+    Hamming,
+    Hann,
+    BlackmanHarris,
+    /// kaiser window with the given beta
+    Kaiser(f32),
+}
+
+impl Window {
+    pub fn coefficients(&self, len: usize) -> Vec<f32> {
+        match self {
+            Window::Hamming => hamming(len),
+            Window::Hann => hann(len),
+            Window::BlackmanHarris => blackman_harris(len),
+            Window::Kaiser(beta) => kaiser(len, *beta),
+        }
+    }
+}
+
+/**
+ * Computes the windowed magnitude spectrum of frames of audio
+ */
+pub struct Spectrum {
+    fft_size: usize,
+    window: Vec<f32>,
+    fft: RealFFT,
+}
+
+impl Spectrum {
+    pub fn new(fft_size: usize, window: Window) -> Spectrum {
+        Spectrum {
+            fft_size,
+            window: window.coefficients(fft_size),
+            fft: RealFFT::new(fft_size),
+        }
+    }
+
+    pub fn scratch(&self) -> FFTScratch {
+        self.fft.scratch()
+    }
+
+    /**
+     * Magnitude spectrum of a window of `fft_size` samples
+     */
+    pub fn magnitude(&self, window: &[f32], scratch: &mut FFTScratch) -> Vec<f32> {
+        self.fft.magnitude(window, &self.window, scratch)
+    }
+
+    /**
+     * Magnitude spectra of many windows, computed in parallel
+     */
+    pub fn magnitudes(&self, windows: &[&[f32]]) -> Vec<Vec<f32>> {
+        windows
+            .par_iter()
+            .map_init(
+                || self.scratch(),
+                |scratch, window| self.magnitude(window, scratch),
+            )
+            .collect()
+    }
+
+    /**
+     * Magnitude spectra of all windows of the audio
+     */
+    pub fn audio_magnitudes(&self, data: &[f32], fft_step: usize) -> Vec<Vec<f32>> {
+        let windows: Vec<&[f32]> = (self.fft_size..data.len())
+            .step_by(fft_step)
+            .map(|i| &data[i - self.fft_size..i])
+            .collect();
+        self.magnitudes(&windows)
+    }
+}
+
+/**
+ * Spectrogram frame of a magnitude spectrum, the bins within the crop limits
+ */
+pub fn spectrogram_frame<'a>(magnitude: &'a [f32], bins: &Range<usize>) -> &'a [f32] {
+    &magnitude[bins.clone()]
+}
+
+/**
+ * Normalize flat spectrogram frames, either each frame to standard score
+ * or each bin by per channel energy normalization
+ */
+pub fn normalize_spectrogram(
+    spectrogram: &[f32],
+    n_bins: usize,
+    params: &FeatureParams,
+) -> Vec<f32> {
+    match params.spectrogram_norm {
+        SpectrogramNorm::Frame => spectrogram
+            .chunks(n_bins)
+            .flat_map(|frame| {
+                let mu_spec = mean(frame);
+                let std_spec = f32::max(std(frame, mu_spec), 1e-6);
+                frame.iter().map(move |x| (x - mu_spec) / std_spec)
+            })
+            .collect(),
+        SpectrogramNorm::Pcen => {
+            let mut pcen = PcenState::new(params.pcen);
+            spectrogram
+                .chunks(n_bins)
+                .flat_map(|frame| pcen.apply(frame))
+                .collect()
+        }
+    }
+}
+
+/**
+ * Cuts a stream of samples into overlapping windows.
+ * Only samples not yet covered by a window are kept.
+ */
+pub struct Framer {
+    size: usize,
+    step: usize,
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Framer {
+    pub fn new(size: usize, step: usize) -> Framer {
+        Framer {
+            size,
+            step,
+            buffer: vec![],
+            pos: 0,
+        }
+    }
+
+    /**
+     * Add the next block of samples
+     */
+    pub fn push(&mut self, block: &[f32]) {
+        let consumed = usize::min(self.pos, self.buffer.len());
+        self.buffer.drain(0..consumed);
+        self.pos -= consumed;
+        self.buffer.extend_from_slice(block);
+    }
+
+    /**
+     * All complete windows, none if more samples are needed
+     */
+    pub fn windows(&mut self) -> Vec<&[f32]> {
+        let mut starts = vec![];
+        while self.pos + self.size < self.buffer.len() {
+            starts.push(self.pos);
+            self.pos += self.step;
+        }
+        let (buffer, size) = (&self.buffer, self.size);
+        starts
+            .into_iter()
+            .map(|start| &buffer[start..start + size])
+            .collect()
+    }
+}
+
+/**
+ * Detector activation of each frame of a streamed and pre processed recording.
+ * Only one block of samples is in memory at a time, the result
+ * holds a single value per frame. The frames are also offered
+ * to the noise sampler, if any.
+ */
+pub fn streamed_activations(
+    stream: &mut AudioStream,
+    preprocessor: &mut Preprocessor,
+    detector: &mut dyn Detector,
+    params: &FeatureParams,
+    mut noise: Option<&mut NoiseSampler>,
+) -> Result<Vec<f32>> {
+    let spectrum = Spectrum::new(params.fft_size, params.window);
+    let mut framer = Framer::new(params.fft_size, params.fft_step);
+    let mut activations = vec![];
+    while let Some(mut block) = stream.next_block()? {
+        preprocessor.process(&mut block);
+        framer.push(&block);
+        for magnitude in spectrum.magnitudes(&framer.windows()) {
+            let activation = detector.activation(&magnitude);
+            if let Some(noise) = noise.as_mut() {
+                noise.push(activation, &magnitude);
+            }
+            activations.push(activation);
+        }
+    }
+    Ok(activations)
+}
+
+/// Frames kept by a noise sampler, about 8 MB for a 2048 point fft
+pub const NOISE_FRAMES: usize = 2048;
+
+/**
+ * Uniform sample of the magnitude spectra of a streamed recording
+ * together with their detector activation. Every `stride`-th frame is kept,
+ * when the sample is full every second frame is dropped and the stride doubled,
+ * so memory stays bounded however long the recording is.
+ */
+pub struct NoiseSampler {
+    capacity: usize,
+    stride: usize,
+    t: usize,
+    frames: Vec<(f32, Vec<f32>)>,
+}
+
+impl NoiseSampler {
+    pub fn new(capacity: usize) -> NoiseSampler {
+        NoiseSampler {
+            capacity,
+            stride: 1,
+            t: 0,
+            frames: vec![],
+        }
+    }
+
+    pub fn push(&mut self, activation: f32, magnitude: &[f32]) {
+        if self.t.is_multiple_of(self.stride) {
+            self.frames.push((activation, magnitude.to_vec()));
+            if self.frames.len() > self.capacity {
+                let mut i = 0;
+                self.frames.retain(|_| {
+                    i += 1;
+                    i % 2 == 1
+                });
+                self.stride *= 2;
+            }
+        }
+        self.t += 1;
+    }
+
+    /**
+     * Noise profile: the mean magnitude spectrum of the sampled frames
+     * whose activation is at most the given percentile of the sample
+     */
+    pub fn profile(&self, perc: f32) -> Result<Vec<f32>> {
+        let mut activations: Vec<f32> = self.frames.iter().map(|(a, _)| *a).collect();
+        let th = percentile(&mut activations, perc).map_err(|_| {
+            DiscoveryError::EmptyData(
+                "no noise frames to estimate the noise profile from".to_string(),
+            )
+        })?;
+        let mut profile = vec![0.0; self.frames[0].1.len()];
+        let mut n = 0;
+        for (_, magnitude) in self.frames.iter().filter(|(a, _)| *a <= th) {
+            for (p, m) in profile.iter_mut().zip(magnitude) {
+                *p += m;
+            }
+            n += 1;
+        }
+        Ok(profile.iter().map(|p| p / n as f32).collect())
+    }
+}
+
+/// Seed of the random start phases of griffin lim
+const GRIFFIN_LIM_SEED: u64 = 1984;
+
+/**
+ * Griffin-Lim phase reconstruction [Griffin and Lim 1984].
+ * Estimates audio with a magnitude spectrogram close to the given one,
+ * starting from random phases and alternating between the signal
+ * and spectra with the given magnitudes and the phases of the signal.
+ */
+pub struct GriffinLim {
+    fft_size: usize,
+    fft_step: usize,
+    window: Vec<f32>,
+    fft: Arc<dyn FFT<f32>>,
+    ifft: Arc<dyn FFT<f32>>,
+}
+
+impl GriffinLim {
+    pub fn new(fft_size: usize, fft_step: usize, window: Window) -> GriffinLim {
+        GriffinLim {
+            fft_size,
+            fft_step,
+            window: window.coefficients(fft_size),
+            fft: FFTplanner::new(false).plan_fft(fft_size),
+            ifft: FFTplanner::new(true).plan_fft(fft_size),
+        }
+    }
+
+    /**
+     * Audio of magnitude spectra of the first half of the bins,
+     * with frames `fft_step` samples apart. The random start phases
+     * are seeded, so the same spectra give the same audio.
+     */
+    pub fn reconstruct(&self, magnitudes: &[Vec<f32>], iterations: usize) -> Vec<f32> {
+        if magnitudes.is_empty() {
+            return vec![];
+        }
+        let len = (magnitudes.len() - 1) * self.fft_step + self.fft_size;
+        let mut norm = vec![0.0; len];
+        for t in 0..magnitudes.len() {
+            for (i, w) in self.window.iter().enumerate() {
+                norm[t * self.fft_step + i] += w * w;
+            }
+        }
+        let mut rng = StdRng::seed_from_u64(GRIFFIN_LIM_SEED);
+        let mut phases: Vec<Vec<Complex<f32>>> = magnitudes
+            .iter()
+            .map(|magnitude| {
+                magnitude
+                    .iter()
+                    .map(|_| {
+                        let phase = rng.gen_range(-std::f32::consts::PI, std::f32::consts::PI);
+                        Complex::new(phase.cos(), phase.sin())
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut signal = self.overlap_add(magnitudes, &phases, &norm);
+        for _ in 0..iterations {
+            phases = self.phases(&signal, magnitudes.len());
+            signal = self.overlap_add(magnitudes, &phases, &norm);
+        }
+        signal
+    }
+
+    /**
+     * Windowed overlap add of the inverse fft of each frame,
+     * normalized by the sum of the overlapping squared windows
+     */
+    fn overlap_add(
+        &self,
+        magnitudes: &[Vec<f32>],
+        phases: &[Vec<Complex<f32>>],
+        norm: &[f32],
+    ) -> Vec<f32> {
+        let n = self.fft_size;
+        let mut signal = vec![0.0; norm.len()];
+        let mut spectrum = vec![Complex::zero(); n];
+        let mut frame = vec![Complex::zero(); n];
+        for (t, (magnitude, phase)) in magnitudes.iter().zip(phases).enumerate() {
+            for x in spectrum.iter_mut() {
+                *x = Complex::zero();
+            }
+            for (k, (m, p)) in magnitude.iter().zip(phase).enumerate().take(n / 2) {
+                spectrum[k] = p * m;
+                if k > 0 {
+                    spectrum[n - k] = spectrum[k].conj();
+                }
+            }
+            self.ifft.process(&mut spectrum, &mut frame);
+            for (i, x) in frame.iter().enumerate() {
+                signal[t * self.fft_step + i] += x.re / n as f32 * self.window[i];
+            }
+        }
+        // the windows barely overlap at the edges, do not amplify them
+        let floor = 0.1 * max(norm);
+        for (x, w) in signal.iter_mut().zip(norm) {
+            *x /= f32::max(*w, floor);
+        }
+        signal
+    }
+
+    /**
+     * Unit phasors of the first half of the bins of each frame of the signal
+     */
+    fn phases(&self, signal: &[f32], n_frames: usize) -> Vec<Vec<Complex<f32>>> {
+        let n = self.fft_size;
+        let mut input = vec![Complex::zero(); n];
+        let mut output = vec![Complex::zero(); n];
+        (0..n_frames)
+            .map(|t| {
+                let frame = &signal[t * self.fft_step..t * self.fft_step + n];
+                for (i, x) in frame.iter().enumerate() {
+                    input[i] = Complex::new(x * self.window[i], 0.0);
+                }
+                self.fft.process(&mut input, &mut output);
+                output[..n / 2]
+                    .iter()
+                    .map(|x| {
+                        let norm = x.norm();
+                        if norm > 1e-12 {
+                            x / norm
+                        } else {
+                            Complex::new(1.0, 0.0)
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/**
+ * Magnitude spectra the features are computed from: after noise reduction
+ * and with the bins outside the spectrogram crop limits set to zero
+ */
+pub fn model_magnitudes(
+    params: &FeatureParams,
+    raw_audio: &AudioData,
+    noise: Option<(&NoiseReduction, &[f32])>,
+) -> Vec<Vec<f32>> {
+    let bins = params.spectrogram_bins(raw_audio.spec.sample_rate);
+    let (magnitudes, _) = NDSequence::spectrograms(params, raw_audio, noise);
+    magnitudes
+        .into_iter()
+        .map(|mut magnitude| {
+            for (k, x) in magnitude.iter_mut().enumerate() {
+                if !bins.contains(&k) {
+                    *x = 0.0;
+                }
+            }
+            magnitude
+        })
+        .collect()
+}
+
+/**
+ * Spectral subtraction of the noise profile of a recording
+ */
+#[derive(Clone, Debug)]
+pub struct NoiseReduction {
+    /// scale of the subtracted noise profile
+    pub over_subtraction: f32,
+    /// fraction of the original magnitude that is always kept
+    pub floor: f32,
+}
+
+impl NoiseReduction {
+    /**
+     * Subtract the noise profile from a magnitude spectrum
+     */
+    pub fn subtract(&self, magnitude: &[f32], profile: &[f32]) -> Vec<f32> {
+        magnitude
+            .iter()
+            .zip(profile)
+            .map(|(m, p)| f32::max(m - self.over_subtraction * p, self.floor * m))
+            .collect()
+    }
+}
+
+/**
+ * Input of the alignment and clustering
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterInput {
+    /// auto encoded feature frames
+    Features,
+    /// normalized spectrogram frames
+    Spectrogram,
+    /// frequency contour of tonal calls
+    Contour,
+}
+
+/**
+ * Parameters of the contour tracker
+ */
+#[derive(Clone, Debug)]
+pub struct ContourParams {
+    /// lowest frequency of the contour in Hz
+    pub min_freq: f32,
+    /// highest frequency of the contour in Hz
+    pub max_freq: f32,
+    /// largest change of the frequency between frames in Hz
+    pub max_jump: f32,
+    /// peak level above the median of the band in dB for voiced frames
+    pub prominence: f32,
+}
+
+/**
+ * Follows the spectral peak of a tonal call frame by frame.
+ * While a contour is tracked, the peak is searched within the largest jump
+ * from the last frequency. When there is no prominent peak close by,
+ * a new contour starts at the strongest peak of the band.
+ */
+pub struct ContourTracker {
+    first: usize,
+    last: usize,
+    bin_hz: f32,
+    /// largest jump in bins
+    max_jump: f32,
+    /// linear peak to median ratio
+    prominence: f32,
+    /// fractional bin of the last voiced frame
+    previous: Option<f32>,
+    band: Vec<f32>,
+}
+
+impl ContourTracker {
+    pub fn new(params: &ContourParams, fft_size: usize, sample_rate: u32) -> ContourTracker {
+        let bins = frequency_bins(params.min_freq, params.max_freq, fft_size, sample_rate);
+        let bin_hz = sample_rate as f32 / fft_size as f32;
+        ContourTracker {
+            first: bins.start,
+            last: bins.end - 1,
+            bin_hz,
+            max_jump: params.max_jump / bin_hz,
+            prominence: f32::powf(10.0, params.prominence / 20.0),
+            previous: None,
+            band: vec![],
+        }
+    }
+
+    /**
+     * Frequency in Hz of the next frame, none if the frame is unvoiced
+     */
+    pub fn track(&mut self, magnitude: &[f32]) -> Option<f32> {
+        let band = &magnitude[self.first..=self.last];
+        self.band.clear();
+        self.band.extend_from_slice(band);
+        self.band
+            .sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let threshold = self.prominence * self.band[self.band.len() / 2] + 1e-9;
+        let local = self.previous.and_then(|previous| {
+            let lowest = f32::max(previous - self.max_jump, 0.0).ceil() as usize;
+            let start = usize::max(lowest, self.first);
+            let stop = usize::min((previous + self.max_jump) as usize, self.last);
+            self.peak(magnitude, start, stop, threshold)
+        });
+        self.previous = local.or_else(|| self.peak(magnitude, self.first, self.last, threshold));
+        self.previous.map(|bin| bin * self.bin_hz)
+    }
+
+    /**
+     * Fractional bin of the strongest peak within the bins,
+     * refined by a parabola through its neighbours
+     */
+    fn peak(&self, magnitude: &[f32], start: usize, stop: usize, threshold: f32) -> Option<f32> {
+        if start > stop {
+            return None;
+        }
+        let mut k = start;
+        for i in start..=stop {
+            if magnitude[i] > magnitude[k] {
+                k = i;
+            }
+        }
+        let peak = magnitude[k];
+        if peak < threshold {
+            return None;
+        }
+        if k == 0 || k + 1 >= magnitude.len() {
+            return Some(k as f32);
+        }
+        let (left, right) = (magnitude[k - 1], magnitude[k + 1]);
+        let curvature = left - 2.0 * peak + right;
+        let offset = if curvature < 0.0 {
+            0.5 * (left - right) / curvature
+        } else {
+            0.0
+        };
+        Some(k as f32 + offset)
+    }
+}
+
+/**
+ * Fill unvoiced frames linearly between the closest voiced frames,
+ * holding the first and last voiced frequency at the edges.
+ * Without voiced frames the contour is zero.
+ */
+pub fn interpolate_unvoiced(track: &[Option<f32>]) -> Vec<f32> {
+    let voiced: Vec<(usize, f32)> = track
+        .iter()
+        .enumerate()
+        .filter_map(|(t, hz)| hz.map(|hz| (t, hz)))
+        .collect();
+    if voiced.is_empty() {
+        return vec![0.0; track.len()];
+    }
+    let mut next = 0;
+    (0..track.len())
+        .map(|t| {
+            while next < voiced.len() && voiced[next].0 < t {
+                next += 1;
+            }
+            match (next.checked_sub(1).map(|i| voiced[i]), voiced.get(next)) {
+                (_, Some((t1, hz1))) if *t1 == t => *hz1,
+                (Some((t0, hz0)), Some((t1, hz1))) => {
+                    hz0 + (hz1 - hz0) * (t - t0) as f32 / (t1 - t0) as f32
+                }
+                (Some((_, hz0)), None) => hz0,
+                (None, Some((_, hz1))) => *hz1,
+                (None, None) => 0.0,
+            }
+        })
+        .collect()
+}
+
 /**
  * A flat Spectrogram / Cepstrum
  */
+#[derive(Serialize, Deserialize)]
 pub struct NDSequence {
     /// number of cepstral components
     pub n_bins: usize,
     /// flat cepstrum data `[x00 ... x0D ... xT0 ... xTD]`
     pub frames: Vec<f32>,
-    /// size of dft
+    /// number of spectrogram bins within the crop limits
     pub dft_win: usize,
     /// spectrogram data `[x00 ... x0D ... xT0 ... xTD]`
     pub spectrogram: Vec<f32>,
+    /// spectrogram data before noise reduction
+    pub raw_spectrogram: Vec<f32>,
     /// id of audio file
     pub audio_id: usize,
 }
 
 impl NDSequence {
     /**
-     * Build a spectrogram from raw audio given a dft window and a step size.
-     * Each resulting frame is normalised to standard score.
+     * Build a spectrogram and feature frames from raw audio
+     * given a dft window, a step size and a feature extractor.
+     * The spectrogram and the features are normalised as configured.
+     * Given a noise profile of the recording, the profile is subtracted
+     * from the magnitude spectrum before feature extraction.
+     * Deltas are appended to each frame after normalization.
      */
     pub fn new(
-        fft_size: usize,
-        fft_step: usize,
-        filter_size: usize,
+        params: &FeatureParams,
         raw_audio: &AudioData,
+        noise: Option<(&NoiseReduction, &[f32])>,
     ) -> NDSequence {
-        let hamming = hamming(fft_size);
-        let triag = triag(fft_size / filter_size);
-        let samples: Vec<Complex<f32>> = raw_audio
-            .data
-            .iter()
-            .map(|x| Complex::new(f32::from(*x), 0.0))
-            .collect();
-        let mut planner_dft = FFTplanner::new(false);
-        let mut planner_dct = DCTplanner::new();
-        let mut ceps: Vec<f32> = Vec::new();
-        let mut spectrogram: Vec<f32> = Vec::new();
-        let fft = planner_dft.plan_fft(fft_size);
-        let n = samples.len();
-        let mut n_bins = 0;
-        for i in (fft_size..n).step_by(fft_step) {
-            let start = i - fft_size;
-            let stop = i;
-            let mut output: Vec<Complex<f32>> = vec![Complex::zero(); fft_size];
-            let mut input: Vec<Complex<f32>> = samples[start..stop]
-                .iter()
-                .enumerate()
-                .map(|(i, x)| x * hamming[i])
-                .collect();
-            fft.process(&mut input[..], &mut output);
-            let result: Vec<f32> = output
-                .iter()
-                .map(|complex| f32::sqrt(complex.norm_sqr()))
-                .take(fft_size / 2)
-                .collect();
-            let mut convolved: Vec<f32> =
-                convolve(&result[0..result.len()], &triag[..], triag.len() / 2)
-                    .iter()
-                    .map(|x| f32::ln(*x + 1e-6))
-                    .collect();
-            let mut cepstrum: Vec<f32> = vec![0f32; convolved.len()];
-            let dct = planner_dct.plan_dct1(convolved.len());
-            dct.process_dct1(&mut convolved, &mut cepstrum);
-            let mu_ceps = mean(&cepstrum[4..cepstrum.len()]);
-            let final_ceps: Vec<f32> = cepstrum.iter().skip(4).map(|c| c - mu_ceps).collect();
-            n_bins = cepstrum.len() - 4;
-            for c in final_ceps.iter() {
-                ceps.push(*c);
-            }
+        let (magnitudes, sequence) = NDSequence::spectrograms(params, raw_audio, noise);
+        let mut extractor = params.extractor(raw_audio.spec.sample_rate);
+        let mut frames: Vec<f32> = Vec::new();
+        for magnitude in magnitudes.iter() {
+            frames.extend(extractor.extract(magnitude));
+        }
+        let n_features = extractor.n_features();
+        let (frames, n_bins) = with_deltas(
+            cmvn(&frames, n_features, params.cmvn),
+            n_features,
+            params.delta_order,
+            params.delta_window,
+        );
+        NDSequence {
+            n_bins,
+            frames,
+            ..sequence
+        }
+    }
+
+    /**
+     * Frequency contour of a tonal call in Hz per frame, tracked on the
+     * same spectrum as the features. Also returns which frames are voiced.
+     * Unvoiced frames are interpolated between the closest voiced frames.
+     */
+    pub fn contour(
+        params: &FeatureParams,
+        contour: &ContourParams,
+        raw_audio: &AudioData,
+        noise: Option<(&NoiseReduction, &[f32])>,
+    ) -> (NDSequence, Vec<bool>) {
+        let (magnitudes, sequence) = NDSequence::spectrograms(params, raw_audio, noise);
+        let sample_rate = raw_audio.spec.sample_rate;
+        let mut tracker = ContourTracker::new(contour, params.fft_size, sample_rate);
+        let track: Vec<Option<f32>> = magnitudes.iter().map(|m| tracker.track(m)).collect();
+        let voiced = track.iter().map(Option::is_some).collect();
+        let sequence = NDSequence {
+            n_bins: 1,
+            frames: interpolate_unvoiced(&track),
+            ..sequence
+        };
+        (sequence, voiced)
+    }
 
-            let mu_spec = mean(&result[10..result.len()]);
-            let std_spec = f32::max(std(&result[10..result.len()], mu_spec), 1.0);
-            for result in result.iter().skip(10) {
-                spectrogram.push((result - mu_spec) / std_spec);
+    /**
+     * Magnitude spectra after noise reduction and a sequence holding
+     * the spectrograms before and after noise reduction, without frames.
+     */
+    fn spectrograms(
+        params: &FeatureParams,
+        raw_audio: &AudioData,
+        noise: Option<(&NoiseReduction, &[f32])>,
+    ) -> (Vec<Vec<f32>>, NDSequence) {
+        let bins = params.spectrogram_bins(raw_audio.spec.sample_rate);
+        let mut magnitudes = Spectrum::new(params.fft_size, params.window)
+            .audio_magnitudes(&raw_audio.data, params.fft_step);
+        let mut raw_spectrogram: Vec<f32> = Vec::new();
+        if let Some((reduction, profile)) = noise {
+            for magnitude in magnitudes.iter() {
+                raw_spectrogram.extend_from_slice(spectrogram_frame(magnitude, &bins));
             }
+            magnitudes = magnitudes
+                .par_iter()
+                .map(|magnitude| reduction.subtract(magnitude, profile))
+                .collect();
         }
-        NDSequence {
+        let mut spectrogram: Vec<f32> = Vec::new();
+        for magnitude in magnitudes.iter() {
+            spectrogram.extend_from_slice(spectrogram_frame(magnitude, &bins));
+        }
+        let dft_win = bins.len();
+        let spectrogram = normalize_spectrogram(&spectrogram, dft_win, params);
+        let raw_spectrogram = match noise {
+            Some(_) => normalize_spectrogram(&raw_spectrogram, dft_win, params),
+            None => spectrogram.clone(),
+        };
+        let sequence = NDSequence {
             audio_id: raw_audio.id,
-            n_bins,
-            frames: ceps,
-            dft_win: fft_size / 2 - 10,
+            n_bins: 0,
+            frames: vec![],
+            dft_win,
             spectrogram,
-        }
+            raw_spectrogram,
+        };
+        (magnitudes, sequence)
+    }
+
+    pub fn from_file(file: &str) -> Result<NDSequence> {
+        let mut fp = File::open(file)?;
+        let mut buf: Vec<u8> = vec![];
+        let _ = fp.read_to_end(&mut buf)?;
+        let decoded: NDSequence = deserialize(&buf)?;
+        Ok(decoded)
+    }
+
+    /// save file
+    pub fn save_file(&self, file: &str) -> Result<()> {
+        let mut fp = File::create(file)?;
+        let encoded: Vec<u8> = serialize(&self)?;
+        fp.write_all(&encoded)?;
+        Ok(())
     }
 
     /**
@@ -100,6 +815,20 @@ impl NDSequence {
         &self.frames[t * self.n_bins..(t + 1) * self.n_bins]
     }
 
+    /**
+     * Sequence of the spectrogram frames instead of the features
+     */
+    pub fn spectrogram_sequence(&self) -> NDSequence {
+        NDSequence {
+            audio_id: self.audio_id,
+            n_bins: self.dft_win,
+            frames: self.spectrogram.clone(),
+            dft_win: self.dft_win,
+            spectrogram: self.spectrogram.clone(),
+            raw_spectrogram: self.raw_spectrogram.clone(),
+        }
+    }
+
     pub fn encoded(&self, nn: &AutoEncoder) -> NDSequence {
         let mut flat = vec![];
         for i in 0..self.len() {
@@ -117,6 +846,7 @@ impl NDSequence {
             frames: flat,
             dft_win: self.dft_win,
             spectrogram: self.spectrogram.clone(),
+            raw_spectrogram: self.raw_spectrogram.clone(),
         }
     }
 
@@ -146,18 +876,35 @@ impl NDSequence {
             .collect()
     }
 
+    /**
+     * Spectrogram before noise reduction as bytes of gray scale image.
+     * The values are min-max normalized.
+     */
+    pub fn img_raw_spec(&self) -> Vec<u8> {
+        let max = max(&self.raw_spectrogram[..]);
+        let min = min(&self.raw_spectrogram[..]);
+        self.raw_spectrogram
+            .iter()
+            .map(|x| ((x - min) / (max - min) * 255.0) as u8)
+            .collect()
+    }
+
     /**
      * Len of cepstrum is the length of the flat spectrogram divided by the number of bins
      */
     pub fn len(&self) -> usize {
-        self.frames.len() / (self.n_bins as usize)
+        self.frames.len() / self.n_bins
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /**
      * Len of spectrogram is the length of the flat spectrogram divided by the number of bins
      */
     pub fn len_spec(&self) -> usize {
-        self.spectrogram.len() / (self.dft_win as usize)
+        self.spectrogram.len() / self.dft_win
     }
 
     /**     
@@ -169,50 +916,19 @@ impl NDSequence {
     }
 
     /**
-     * Variance in each frame, smoothed by moving average
+     * Deviation of each frame
      **/
-    pub fn variance(&self, k: usize) -> Vec<f32> {
-        let mut deltas = vec![];
-        for i in 0..self.len() {
-            let m = mean(self.vec(i));
-            let s = std(self.vec(i), m);
-            deltas.push(s);
-        }
-        let mut moving_avg = vec![];
-        for i in 0..self.len() {
-            let avg = if i >= k { mean(&deltas[i - k..i]) } else { 0.0 };
-            moving_avg.push(avg);
-        }
-        moving_avg
+    pub fn deviations(&self) -> Vec<f32> {
+        (0..self.len())
+            .map(|i| std(self.vec(i), mean(self.vec(i))))
+            .collect()
     }
 
     /**
-     * Extract regions by  
+     * Variance in each frame, smoothed by moving average
      **/
-    pub fn interesting_ranges(
-        &self,
-        moving_average: usize,
-        perc: f32,
-        min_len: usize,
-    ) -> Vec<Slice> {
-        let th = percentile(&mut self.variance(moving_average), perc);
-        let variances = self.variance(moving_average);
-        let mut ranges = vec![];
-        let mut start = 0;
-        let mut recording = true;
-        for (i, variance) in variances.iter().enumerate() {
-            if *variance >= th && !recording {
-                start = i;
-                recording = true;
-            }
-            if *variance < th && recording {
-                recording = false;
-                if i - start > min_len {
-                    ranges.push(Slice::new(start, i, self));
-                }
-            }
-        }
-        ranges
+    pub fn variance(&self, k: usize) -> Vec<f32> {
+        moving_avg(&self.deviations(), k)
     }
 }
 
@@ -239,6 +955,10 @@ impl<'a> Slice<'a> {
         self.stop - self.start
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /**
      * Materialise the range as a new spectrogram
      */
@@ -250,6 +970,7 @@ impl<'a> Slice<'a> {
         let spec_start = self.start * self.sequence.dft_win;
         let spec_stop = self.stop * self.sequence.dft_win;
         let spectrogram = Vec::from(&self.sequence.spectrogram[spec_start..spec_stop]);
+        let raw_spectrogram = Vec::from(&self.sequence.raw_spectrogram[spec_start..spec_stop]);
         let dft_win = self.sequence.dft_win;
         let audio_id = self.sequence.audio_id;
         NDSequence {
@@ -258,6 +979,119 @@ impl<'a> Slice<'a> {
             frames,
             dft_win,
             spectrogram,
+            raw_spectrogram,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_frames_match_the_whole_recording() {
+        let data: Vec<f32> = (0..10000).map(|i| i as f32).collect();
+        let (size, step) = (512, 160);
+        let whole: Vec<&[f32]> = (size..data.len())
+            .step_by(step)
+            .map(|i| &data[i - size..i])
+            .collect();
+        for &block in &[1, 100, 333, 513, 1000, 9999, 20000] {
+            let mut framer = Framer::new(size, step);
+            let mut frames: Vec<Vec<f32>> = vec![];
+            for chunk in data.chunks(block) {
+                framer.push(chunk);
+                frames.extend(framer.windows().into_iter().map(|w| w.to_vec()));
+            }
+            assert_eq!(frames.len(), whole.len(), "block {}", block);
+            for (frame, expected) in frames.iter().zip(whole.iter()) {
+                assert_eq!(&frame[..], *expected, "block {}", block);
+            }
+        }
+    }
+
+    fn contour_params(max_jump: f32) -> ContourParams {
+        ContourParams {
+            min_freq: 500.0,
+            max_freq: 4000.0,
+            max_jump,
+            prominence: 10.0,
+        }
+    }
+
+    #[test]
+    fn linear_sweep_is_tracked_within_one_bin() {
+        let (sample_rate, fft_size, fft_step) = (16000, 512, 128);
+        let (f0, f1, seconds) = (1000.0, 3000.0, 1.0);
+        let data: Vec<f32> = (0..sample_rate)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                let phase = f0 * t + (f1 - f0) * t * t / (2.0 * seconds);
+                f32::sin(2.0 * std::f32::consts::PI * phase)
+            })
+            .collect();
+        let magnitudes = Spectrum::new(fft_size, Window::Hann).audio_magnitudes(&data, fft_step);
+        let mut tracker = ContourTracker::new(&contour_params(200.0), fft_size, sample_rate);
+        let bin_hz = sample_rate as f32 / fft_size as f32;
+        for (frame, magnitude) in magnitudes.iter().enumerate() {
+            let center = (frame * fft_step + fft_size / 2) as f32 / sample_rate as f32;
+            let expected = f0 + (f1 - f0) * center / seconds;
+            let tracked = tracker.track(magnitude).unwrap();
+            assert!(
+                (tracked - expected).abs() < bin_hz,
+                "frame {}: {} Hz tracked, {} Hz expected",
+                frame,
+                tracked,
+                expected
+            );
+        }
+    }
+
+    fn peaks(bins: &[(usize, f32)]) -> Vec<f32> {
+        let mut magnitude = vec![0.01; 257];
+        for &(bin, level) in bins {
+            magnitude[bin] = level;
         }
+        magnitude
+    }
+
+    #[test]
+    fn contour_jumps_are_limited() {
+        // 31.25 Hz per bin, jumps of up to 4 bins
+        let mut tracker = ContourTracker::new(&contour_params(125.0), 512, 16000);
+        assert_eq!(tracker.track(&peaks(&[(40, 1.0)])), Some(1250.0));
+        // a weaker peak within the jump is preferred over a stronger one further away
+        assert_eq!(
+            tracker.track(&peaks(&[(43, 0.5), (80, 1.0)])),
+            Some(1343.75)
+        );
+        assert_eq!(
+            tracker.track(&peaks(&[(47, 0.5), (80, 1.0)])),
+            Some(1468.75)
+        );
+        // without a peak close by a new contour starts at the strongest peak
+        assert_eq!(tracker.track(&peaks(&[(52, 0.5), (80, 1.0)])), Some(2500.0));
+        assert_eq!(tracker.track(&peaks(&[])), None);
+        assert_eq!(tracker.track(&peaks(&[(20, 0.5), (60, 1.0)])), Some(1875.0));
+    }
+
+    #[test]
+    fn unvoiced_frames_are_interpolated() {
+        let track = [
+            None,
+            None,
+            Some(100.0),
+            None,
+            None,
+            Some(400.0),
+            Some(300.0),
+            None,
+        ];
+        assert_eq!(
+            interpolate_unvoiced(&track),
+            vec![100.0, 100.0, 100.0, 200.0, 300.0, 400.0, 300.0, 300.0]
+        );
+        assert_eq!(interpolate_unvoiced(&[None, None, None]), vec![0.0; 3]);
+        assert_eq!(interpolate_unvoiced(&[]), Vec::<f32>::new());
     }
 }